@@ -1,6 +1,6 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::f64;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 
@@ -12,6 +12,9 @@ pub struct PageState {
     pub height: f64,
     pub device_pixel_ratio: f64,
     pub is_resized: bool,
+    /// Whether the context was created with an alpha channel, which is needed to draw
+    /// with a transparent background.
+    pub alpha: bool,
     pub ctx: web_sys::CanvasRenderingContext2d,
 }
 
@@ -19,10 +22,19 @@ pub fn window() -> web_sys::Window {
     web_sys::window().expect("no global `window` exists")
 }
 
-pub fn request_animation_frame(f: &Closure<FnMut()>) {
-    window()
-        .request_animation_frame(f.as_ref().unchecked_ref())
-        .expect("should register `requestAnimationFrame` OK");
+#[wasm_bindgen]
+extern "C" {
+    /// requestAnimationFrame is available on both the window and in a dedicated worker, so
+    /// bind to the global function rather than going through the window.
+    #[wasm_bindgen(js_name = requestAnimationFrame)]
+    fn global_request_animation_frame(callback: &js_sys::Function) -> i32;
+
+    #[wasm_bindgen(js_name = cancelAnimationFrame)]
+    fn global_cancel_animation_frame(handle: i32);
+}
+
+pub fn request_animation_frame(f: &Closure<FnMut(f64)>) -> i32 {
+    global_request_animation_frame(f.as_ref().unchecked_ref())
 }
 
 pub fn document() -> web_sys::Document {
@@ -35,43 +47,44 @@ pub fn body() -> web_sys::HtmlElement {
     document().body().expect("document should have a body")
 }
 
-pub fn window_device_pixel_width() -> f64 {
-    window().device_pixel_ratio()
-        * window()
-            .inner_width()
-            .expect("got the window inner width")
-            .as_f64()
-            .expect("turned the width to a f64")
-}
-
-pub fn window_device_pixel_height() -> f64 {
-    window().device_pixel_ratio()
-        * window()
-            .inner_height()
-            .expect("got the window inner width")
-            .as_f64()
-            .expect("turned the width to a f64")
-}
-
 #[derive(Deserialize, Serialize)]
 struct ContextOptions {
     alpha: bool,
 }
 
-pub fn get_context() -> web_sys::CanvasRenderingContext2d {
-    let canvas = document()
-        .get_element_by_id("canvas")
-        .expect("A canvas element must be selected.");
-
-    let canvas: web_sys::HtmlCanvasElement = canvas
+/// Find a canvas element from a CSS selector, e.g. "#canvas".
+pub fn query_canvas(selector: &str) -> Result<web_sys::HtmlCanvasElement, JsValue> {
+    document()
+        .query_selector(selector)?
+        .ok_or_else(|| JsValue::from_str(&format!("No element matched \"{}\"", selector)))?
         .dyn_into::<web_sys::HtmlCanvasElement>()
-        .map_err(|_| ())
-        .unwrap();
+        .map_err(|_| JsValue::from_str(&format!("The element \"{}\" is not a canvas", selector)))
+}
 
+/// Get a canvas from JS, which is either a canvas element, or a CSS selector for one.
+pub fn canvas_from_js(canvas: JsValue) -> Result<web_sys::HtmlCanvasElement, JsValue> {
+    match canvas.as_string() {
+        Some(selector) => query_canvas(&selector),
+        None => canvas
+            .dyn_into::<web_sys::HtmlCanvasElement>()
+            .map_err(|_| JsValue::from_str("Expected a canvas element or a selector.")),
+    }
+}
+
+/// The canvas can opt into a transparent background with a data-transparent attribute. This
+/// needs to be known before the context is created.
+pub fn canvas_is_transparent(canvas: &web_sys::HtmlCanvasElement) -> bool {
+    (canvas.as_ref() as &web_sys::Element).has_attribute("data-transparent")
+}
+
+pub fn get_context(
+    canvas: &web_sys::HtmlCanvasElement,
+    alpha: bool,
+) -> web_sys::CanvasRenderingContext2d {
     canvas
         .get_context_with_context_options(
             "2d",
-            &JsValue::from_serde(&ContextOptions { alpha: false }).unwrap(),
+            &JsValue::from_serde(&ContextOptions { alpha }).unwrap(),
         )
         .unwrap()
         .unwrap()
@@ -79,39 +92,166 @@ pub fn get_context() -> web_sys::CanvasRenderingContext2d {
         .unwrap()
 }
 
-pub fn set_canvas_to_window_size() {
-    let canvas = document()
-        .get_element_by_id("canvas")
-        .expect("A canvas element must be selected.");
+/// The canvas can be drawn from a worker with a data-worker attribute, where the value is
+/// the URL of the worker script, e.g. data-worker="worker.js".
+pub fn canvas_worker_url(canvas: &web_sys::HtmlCanvasElement) -> Option<String> {
+    (canvas.as_ref() as &web_sys::Element).get_attribute("data-worker")
+}
 
+pub fn get_offscreen_context(
+    canvas: &web_sys::OffscreenCanvas,
+    alpha: bool,
+) -> Result<web_sys::CanvasRenderingContext2d, JsValue> {
     canvas
-        .set_attribute("width", &ToString::to_string(&window_device_pixel_width()))
+        .get_context_with_context_options(
+            "2d",
+            &JsValue::from_serde(&ContextOptions { alpha }).unwrap(),
+        )?
+        .ok_or_else(|| JsValue::from_str("Unable to get a 2d context for the OffscreenCanvas."))
+        // The OffscreenCanvasRenderingContext2D is not in web_sys, but it supports all of
+        // the drawing calls that are used here, so treat it as a regular 2d context.
+        .map(|context| context.unchecked_into::<web_sys::CanvasRenderingContext2d>())
+}
+
+/// Set the size of the canvas in device pixels. The backing store can only have a whole
+/// number of pixels, so the size is rounded.
+pub fn set_canvas_size(canvas: &web_sys::HtmlCanvasElement, width: f64, height: f64) {
+    canvas.set_width(width.round() as u32);
+    canvas.set_height(height.round() as u32);
+}
+
+/// Set the size of the canvas in device pixels to match the size it takes up on the page.
+pub fn set_canvas_to_client_size(canvas: &web_sys::HtmlCanvasElement) {
+    let (width, height) = element_device_pixel_size(canvas.as_ref());
+    set_canvas_size(canvas, width, height);
+}
+
+/// Set the size of the canvas in CSS pixels, so that it no longer depends on the page layout.
+pub fn set_canvas_css_size(canvas: &web_sys::HtmlCanvasElement, width: f64, height: f64) {
+    let style = (canvas.as_ref() as &web_sys::HtmlElement).style();
+    style
+        .set_property("width", &format!("{}px", width))
         .unwrap();
-    canvas
-        .set_attribute(
-            "height",
-            &ToString::to_string(&window_device_pixel_height()),
-        )
+    style
+        .set_property("height", &format!("{}px", height))
         .unwrap();
 }
 
-pub fn start_raf<F>(mut callback: F)
+/// When nothing in the CSS gives the canvas a size, it takes up the size of its backing store
+/// on the page. Sizing the backing store to the device pixels would then grow the canvas on
+/// every resize, so pin the CSS size to the current size in that case. This checks whether
+/// the size on the page follows the backing store by changing it a little, so it must only
+/// be called before anything is drawn.
+pub fn pin_intrinsic_css_size(canvas: &web_sys::HtmlCanvasElement) {
+    let element: &web_sys::Element = canvas.as_ref();
+    let (width, height) = (canvas.width(), canvas.height());
+    let (client_width, client_height) = (element.client_width(), element.client_height());
+    canvas.set_width(width + 1);
+    canvas.set_height(height + 1);
+    let is_intrinsic_width = element.client_width() != client_width;
+    let is_intrinsic_height = element.client_height() != client_height;
+    canvas.set_width(width);
+    canvas.set_height(height);
+
+    let style = (canvas.as_ref() as &web_sys::HtmlElement).style();
+    if is_intrinsic_width {
+        style
+            .set_property("width", &format!("{}px", client_width))
+            .unwrap();
+    }
+    if is_intrinsic_height {
+        style
+            .set_property("height", &format!("{}px", client_height))
+            .unwrap();
+    }
+}
+
+/// The closure of an animation loop, which is taken out once the loop is dropped.
+type AnimationLoopClosure = Rc<RefCell<Option<Closure<FnMut(f64)>>>>;
+
+/// A requestAnimationFrame loop, which stops once it's dropped.
+pub struct AnimationLoop {
+    closure: AnimationLoopClosure,
+    handle: Rc<Cell<i32>>,
+}
+
+impl Drop for AnimationLoop {
+    fn drop(&mut self) {
+        global_cancel_animation_frame(self.handle.get());
+        // This can happen from within the callback, in which case wasm-bindgen holds onto
+        // the closure until the callback returns.
+        self.closure.borrow_mut().take();
+    }
+}
+
+/// Call the callback on every animation frame, with the timestamp of the frame in
+/// milliseconds, until the returned loop is dropped.
+pub fn start_raf<F>(mut callback: F) -> AnimationLoop
 where
     // The function passed in is mutable, and each reference has to have a lifetime
     // at least equal to 'static. The function itself is not static.
-    F: FnMut() + 'static,
+    F: FnMut(f64) + 'static,
 {
-    // Create a self-referential reference counted cell. This cell contains our
-    // closure which will be looped over, and kept alive.
+    // The closure requests the next frame with itself, so it only holds a weak reference to
+    // the cell it's kept alive in. Otherwise the loop could never be dropped.
     let f = Rc::new(RefCell::new(None));
-    let g = f.clone();
-    *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
-        callback();
-        request_animation_frame(f.borrow().as_ref().unwrap());
-    }) as Box<FnMut()>));
+    let handle = Rc::new(Cell::new(0));
+    let g = Rc::downgrade(&f);
+    *f.borrow_mut() = Some(Closure::wrap(Box::new({
+        let handle = handle.clone();
+        move |timestamp| {
+            callback(timestamp);
+            // The loop may have been dropped by the callback.
+            if let Some(f) = g.upgrade() {
+                if let Some(ref closure) = *f.borrow() {
+                    handle.set(request_animation_frame(closure));
+                }
+            }
+        }
+    }) as Box<FnMut(f64)>));
 
     // Kick off the raf loop.
-    request_animation_frame(g.borrow().as_ref().unwrap());
+    handle.set(request_animation_frame(f.borrow().as_ref().unwrap()));
+    AnimationLoop { closure: f, handle }
+}
+
+/// An event listener, which is removed from its target once it's dropped.
+pub struct EventListener {
+    target: web_sys::EventTarget,
+    event_name: String,
+    closure: Closure<FnMut(JsValue)>,
+}
+
+impl Drop for EventListener {
+    fn drop(&mut self) {
+        let _ = self.target.remove_event_listener_with_callback(
+            &self.event_name,
+            self.closure.as_ref().unchecked_ref(),
+        );
+    }
+}
+
+/// Listen to an event on the target, until the returned listener is dropped.
+pub fn add_event_listener<T, F>(
+    target: &web_sys::EventTarget,
+    event_name: &str,
+    mut callback: F,
+) -> EventListener
+where
+    T: JsCast,
+    F: FnMut(T) + 'static,
+{
+    let closure = Closure::wrap(
+        Box::new(move |event: JsValue| callback(event.unchecked_into())) as Box<FnMut(JsValue)>,
+    );
+    target
+        .add_event_listener_with_callback(event_name, closure.as_ref().unchecked_ref())
+        .unwrap();
+    EventListener {
+        target: target.clone(),
+        event_name: event_name.to_string(),
+        closure,
+    }
 }
 
 pub fn on_window_resize<F>(callback: F)
@@ -124,3 +264,151 @@ where
         .unwrap();
     closure.forget();
 }
+
+#[wasm_bindgen]
+extern "C" {
+    /// The ResizeObserver is not available in web_sys, so bind to it directly.
+    type ResizeObserver;
+
+    #[wasm_bindgen(constructor)]
+    fn new(callback: &js_sys::Function) -> ResizeObserver;
+
+    #[wasm_bindgen(method)]
+    fn observe(this: &ResizeObserver, target: &web_sys::Element);
+
+    #[wasm_bindgen(method)]
+    fn disconnect(this: &ResizeObserver);
+}
+
+/// Watches the size of an element, until it's dropped.
+pub struct ElementResizeObserver {
+    observer: ResizeObserver,
+    _closure: Closure<FnMut()>,
+}
+
+impl Drop for ElementResizeObserver {
+    fn drop(&mut self) {
+        self.observer.disconnect();
+    }
+}
+
+/// Call the callback every time the size of an element changes, until the returned observer
+/// is dropped.
+pub fn on_element_resize<F>(element: &web_sys::Element, callback: F) -> ElementResizeObserver
+where
+    F: FnMut() + 'static,
+{
+    let closure = Closure::wrap(Box::new(callback) as Box<FnMut()>);
+    let observer = ResizeObserver::new(closure.as_ref().unchecked_ref());
+    observer.observe(element);
+    ElementResizeObserver {
+        observer,
+        _closure: closure,
+    }
+}
+
+/// Watches the device pixel ratio, until it's dropped.
+pub struct DevicePixelRatioWatcher {
+    /// The listener for the media query of the current ratio.
+    _listener: Rc<RefCell<Option<EventListener>>>,
+}
+
+/// Call the callback every time the device pixel ratio changes, e.g. when the page is zoomed,
+/// or the window is moved to a display with a different density. The resize event doesn't
+/// cover all of these cases. This continues until the returned watcher is dropped.
+pub fn on_device_pixel_ratio_change<F>(callback: F) -> DevicePixelRatioWatcher
+where
+    F: FnMut() + 'static,
+{
+    let listener = Rc::new(RefCell::new(None));
+    watch_device_pixel_ratio(Rc::new(RefCell::new(callback)), Rc::downgrade(&listener));
+    DevicePixelRatioWatcher {
+        _listener: listener,
+    }
+}
+
+fn watch_device_pixel_ratio(
+    callback: Rc<RefCell<FnMut()>>,
+    listener: Weak<RefCell<Option<EventListener>>>,
+) {
+    let listener_cell = match listener.upgrade() {
+        Some(listener_cell) => listener_cell,
+        // The watcher was dropped.
+        None => return,
+    };
+    // The media query only matches the current ratio, so it changes once the ratio changes.
+    // After that a new query is needed for the new ratio, which replaces the old listener.
+    let query = format!("(resolution: {}dppx)", window().device_pixel_ratio());
+    let media_query_list = match window().match_media(&query) {
+        Ok(Some(media_query_list)) => media_query_list,
+        _ => return,
+    };
+    let new_listener = add_event_listener(
+        media_query_list.as_ref(),
+        "change",
+        move |_: web_sys::Event| {
+            (callback.borrow_mut())();
+            watch_device_pixel_ratio(callback.clone(), listener.clone());
+        },
+    );
+    *listener_cell.borrow_mut() = Some(new_listener);
+}
+
+/// Listen to a pointer event on the canvas, e.g. "pointerdown" or "pointerup". Pointer events
+/// cover both the mouse and touch input.
+pub fn on_canvas_pointer_event<F>(
+    canvas: &web_sys::HtmlCanvasElement,
+    event_name: &str,
+    callback: F,
+) -> EventListener
+where
+    F: FnMut(web_sys::PointerEvent) + 'static,
+{
+    add_event_listener(canvas.as_ref(), event_name, callback)
+}
+
+/// Keep sending the events for a pointer to the canvas, even when it leaves the canvas, until
+/// the pointer is released.
+pub fn capture_pointer(canvas: &web_sys::HtmlCanvasElement, event: &web_sys::PointerEvent) {
+    // This fails if the pointer was already released, in which case there's nothing to do.
+    let _ = (canvas.as_ref() as &web_sys::Element).set_pointer_capture(event.pointer_id());
+}
+
+pub fn on_canvas_wheel_event<F>(canvas: &web_sys::HtmlCanvasElement, callback: F) -> EventListener
+where
+    F: FnMut(web_sys::WheelEvent) + 'static,
+{
+    add_event_listener(canvas.as_ref(), "wheel", callback)
+}
+
+/// Dispatch a CustomEvent, with the detail passed along to the listeners.
+pub fn dispatch_custom_event(target: &web_sys::EventTarget, event_name: &str, detail: &JsValue) {
+    let mut init = web_sys::CustomEventInit::new();
+    init.detail(detail);
+    let event = web_sys::CustomEvent::new_with_event_init_dict(event_name, &init)
+        .expect("Unable to create a CustomEvent");
+    target.dispatch_event(&event).unwrap();
+}
+
+/// The size of the element on the page, rounded to whole device pixels.
+pub fn element_device_pixel_size(element: &web_sys::Element) -> (f64, f64) {
+    let device_pixel_ratio = window().device_pixel_ratio();
+    (
+        (element.client_width() as f64 * device_pixel_ratio).round(),
+        (element.client_height() as f64 * device_pixel_ratio).round(),
+    )
+}
+
+/// Convert the client coordinates of a mouse or pointer event into the device pixel space
+/// of the canvas.
+pub fn canvas_device_pixel_position(
+    canvas: &web_sys::HtmlCanvasElement,
+    event: &web_sys::MouseEvent,
+) -> (f64, f64) {
+    let rect = (canvas.as_ref() as &web_sys::Element).get_bounding_client_rect();
+    let device_pixel_ratio = window().device_pixel_ratio();
+    (
+        (event.client_x() as f64 - rect.left()) * device_pixel_ratio,
+        (event.client_y() as f64 - rect.top()) * device_pixel_ratio,
+    )
+}
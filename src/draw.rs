@@ -6,11 +6,25 @@ extern crate wasm_bindgen;
 extern crate web_sys;
 
 use self::spade::rtree::RTree;
+use cgmath::{Point2, Vector2};
 use dom::PageState;
+use export;
+use export::Exporter;
+use gallery;
+use gallery::Gallery;
+use random::Random;
+use scheduler::Scheduler;
+use stats;
+use stats::Stats;
 use std::cell::RefCell;
 use std::rc::Rc;
-use tree_node::{MutableNodes, TreeNode};
+use stroke::{PathCache, StrokeBatches};
+use style::{ResolvedStyle, Style, WidthBy};
+use timeline;
+use timeline::Playback;
+use tree_node::{GrowthParameters, MutableNodes, TreeNode, TreeNodeIndex};
 use tree_node_ref::TreeNodeReference;
+use view::{Boundary, Camera, Framing, ResizeBehavior, ViewTransform};
 use wasm_bindgen::prelude::*;
 
 #[allow(unused_imports)]
@@ -30,114 +44,505 @@ pub struct State {
     /// This flag gets changed when it's necessary to completely redraw the visualization.
     /// This is potentially an expensive operation, so care has been taken to limit re-draws.
     pub force_redraw: bool,
-    /// How many times tick has been called.
+    /// How many growth steps have been run.
     pub tick_count: u32,
     /// Remember the current state of the page we are on, such as width and height.
     pub page: PageState,
     /// This is a data structure to help speed up intersection tests for nodes.
     pub r_tree: RTree<TreeNodeReference>,
+    /// The independent trees that are growing. They all share the same nodes and R-tree, so
+    /// they compete with each other for space.
+    pub roots: Vec<Root>,
+    /// The camera can be panned, zoomed and rotated to inspect the visualization.
+    pub camera: Camera,
+    /// How the unit interval space is framed within the canvas.
+    pub framing: Framing,
+    /// The region that the trees can split within.
+    pub boundary: Boundary,
+    /// How the lines are colored.
+    pub style: Style,
+    /// Spreads the growth of the trees across frames.
+    pub scheduler: Scheduler,
+    /// The paths of the lines that are done changing, for replaying on a full redraw.
+    pub path_cache: PathCache,
+    /// When this is set, the recorded timeline is being played back instead of growing.
+    pub playback: Option<Playback>,
+    /// When this is set, the frames are being exported.
+    pub exporter: Option<Exporter>,
+    /// Statistics on how the trees grew.
+    pub stats: Stats,
+    /// Set once every tip has stopped growing. There is nothing left to do until something
+    /// changes, so the ticks are skipped.
+    pub is_complete: bool,
+    /// The final statistics once the growth completes, waiting to be handed off to JS.
+    pub completion: Option<stats::Summary>,
+    /// When this is set, the trees are regrown every time they complete.
+    pub gallery: Option<Gallery>,
+    /// The growth parameters for the roots seeded from the pointer, which come from the
+    /// last preset.
+    pub growth: GrowthParameters,
+    /// The random numbers for growing the trees, which can be seeded for an export.
+    pub rng: Random,
+}
+
+impl State {
+    /// The current mapping from the unit interval space into canvas device pixel space.
+    pub fn view(&self) -> ViewTransform {
+        ViewTransform::new(&self.page, &self.camera, &self.framing)
+    }
+}
+
+/// A root is the base node of an independent tree, and is drawn with its own color.
+#[derive(Debug)]
+pub struct Root {
+    pub node_index: TreeNodeIndex,
+    /// The config the root was seeded with, so that it can be grown again.
+    pub config: RootConfig,
+}
+
+/// The configuration to seed a new root. This can come from JS, so it can be deserialized,
+/// with any missing values falling back to the defaults. The position is in the same unit
+/// interval space as the nodes, and the heading is an angle in radians.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RootConfig {
+    pub x: f64,
+    pub y: f64,
+    pub heading: f64,
+    pub color: String,
+    pub growth: GrowthParameters,
+}
+
+impl Default for RootConfig {
+    fn default() -> RootConfig {
+        RootConfig {
+            x: 0.0,
+            y: 0.0,
+            heading: 0.0,
+            color: String::from("#fff"),
+            growth: GrowthParameters::default(),
+        }
+    }
+}
+
+/// The initial roots start in the corners and grow inwards.
+pub fn default_roots() -> Vec<RootConfig> {
+    let quarter = std::f64::consts::PI * 0.25;
+    // Variable naming: left, right, bottom, top - l, r, b, t
+    let lt = (-0.25, -0.5, quarter);
+    let lb = (-0.5, 0.25, -quarter);
+    let rt = (0.5, -0.5, quarter * 3.0);
+    let rb = (0.5, 0.5, -quarter * 3.0);
+
+    [lt, lb, rt, rb]
+        .iter()
+        .map(|&(x, y, heading)| RootConfig {
+            x,
+            y,
+            heading,
+            ..RootConfig::default()
+        })
+        .collect()
 }
 
 /// Initialize the state for the first time. The page and canvas have already been set up, but
 /// now we want to intialize the State for this particular visualization.
 pub fn init(page: PageState) -> State {
-    let mut r_tree = RTree::new();
-
-    // Create the initial nodes.
-    let mut tree = TreeNode::new(0.0, 0.0, 0.0, 0.0, 0);
-    let l = tree.limb_length;
-    // Variable naming: left, right, bottom, top - l, r, b, t
-    let lt = TreeNode::new(-0.25, -0.5, -0.25 + l, -0.5 + l, 1);
-    let lb = TreeNode::new(-0.5, 0.25, -0.5 + l, 0.25 - l, 1);
-    let rt = TreeNode::new(0.5, -0.5, 0.5 - l, -0.5 + l, 1);
-    let rb = TreeNode::new(0.5, 0.5, 0.5 - l, 0.5 - l, 1);
-    {
-        tree.children.push(1);
-        tree.children.push(2);
-        tree.children.push(3);
-        tree.children.push(4);
-    };
-    r_tree.insert(TreeNodeReference::from_node(&tree, 0));
-    r_tree.insert(TreeNodeReference::from_node(&lt, 1));
-    r_tree.insert(TreeNodeReference::from_node(&lb, 2));
-    r_tree.insert(TreeNodeReference::from_node(&rt, 3));
-    r_tree.insert(TreeNodeReference::from_node(&rb, 4));
-
-    let nodes = RefCell::new(vec![
-        Rc::new(RefCell::new(tree)),
-        Rc::new(RefCell::new(lt)),
-        Rc::new(RefCell::new(lb)),
-        Rc::new(RefCell::new(rt)),
-        Rc::new(RefCell::new(rb)),
-    ]);
-
-    State {
-        nodes,
+    let mut state = State {
+        nodes: RefCell::new(Vec::new()),
         page,
         force_redraw: true,
         tick_count: 0,
-        r_tree,
+        r_tree: RTree::new(),
+        roots: Vec::new(),
+        camera: Camera::default(),
+        framing: Framing::default(),
+        boundary: Boundary::default(),
+        style: Style::default(),
+        scheduler: Scheduler::default(),
+        path_cache: PathCache::default(),
+        playback: None,
+        exporter: None,
+        stats: Stats::default(),
+        is_complete: false,
+        completion: None,
+        gallery: None,
+        growth: GrowthParameters::default(),
+        rng: Random::new(),
+    };
+
+    for config in default_roots() {
+        add_root(&mut state, &config);
     }
+
+    state
+}
+
+/// Seed a new independent tree. The root is checked against all of the existing nodes, so it
+/// can be added at any time while the other trees are still growing.
+pub fn add_root(state: &mut State, config: &RootConfig) -> TreeNodeIndex {
+    let node_index = state.nodes.borrow().len();
+    let mut node = TreeNode::with_parameters(
+        config.x,
+        config.y,
+        config.x,
+        config.y,
+        0,
+        config.growth,
+        &mut state.rng,
+    );
+    node.end.x += config.heading.cos() * node.limb_length;
+    node.end.y += config.heading.sin() * node.limb_length;
+    node.root_index = state.roots.len();
+    node.created_tick = state.tick_count;
+    node.insert_and_clip(node_index, &state.nodes, &mut state.r_tree);
+    state.stats.add_node(&node);
+
+    state.nodes.borrow_mut().push(Rc::new(RefCell::new(node)));
+    state.scheduler.add_tip(node_index);
+    state.is_complete = false;
+    state.roots.push(Root {
+        node_index,
+        config: config.clone(),
+    });
+
+    node_index
+}
+
+/// The minimum distance in CSS pixels before the pointer movement counts as a drag.
+pub const DRAG_THRESHOLD: f64 = 10.0;
+
+/// Seed a new tree from a pointer interaction on the canvas, where the points are in device
+/// pixels. A click grows a tree towards the center, while a drag grows it in the direction
+/// of the drag.
+pub fn add_root_from_pointer(state: &mut State, down: (f64, f64), up: (f64, f64)) {
+    let drag_threshold = DRAG_THRESHOLD * state.page.device_pixel_ratio;
+    let view = state.view();
+    let start = view.page_to_unit(Vector2::new(down.0, down.1));
+    let heading = if (up.0 - down.0).hypot(up.1 - down.1) < drag_threshold {
+        (-start.y).atan2(-start.x)
+    } else {
+        let end = view.page_to_unit(Vector2::new(up.0, up.1));
+        (end.y - start.y).atan2(end.x - start.x)
+    };
+
+    add_root(
+        state,
+        &RootConfig {
+            x: start.x,
+            y: start.y,
+            heading,
+            growth: state.growth,
+            ..RootConfig::default()
+        },
+    );
 }
 
-/// The tick is called for every requestAnimationFrame. It delegates out to the update and
-/// draw calls for the visualization.
-pub fn tick(state: &mut State) {
-    let base_node = state
-        .nodes
-        .borrow()
-        .get(0)
-        .expect("There must be at least 1 node.")
-        .clone();
+/// A summary of a single node, that can be handed to JS, e.g. for hover tooltips.
+#[derive(Debug, Serialize)]
+pub struct NodeInfo {
+    pub index: TreeNodeIndex,
+    pub start: (f64, f64),
+    pub end: (f64, f64),
+    pub depth: i32,
+    pub growth_length: f64,
+    pub children: Vec<TreeNodeIndex>,
+}
+
+/// Find the node that is closest to a point in canvas device pixel space, within a small
+/// radius. The R-tree narrows down the candidates, then the true distance to each line is
+/// measured.
+pub fn node_at(state: &State, x: f64, y: f64) -> Option<NodeInfo> {
+    // How close in device pixels the point must be to the line.
+    let hit_radius = 8.0 * state.page.device_pixel_ratio;
+    let view = state.view();
+    let point = view.page_to_unit(Vector2::new(x, y));
+    let radius = hit_radius / view.scale;
+
+    let nodes_borrow = state.nodes.borrow();
+    state
+        .r_tree
+        .lookup_in_circle(&Point2::new(point.x, point.y), &(radius * radius))
+        .iter()
+        .map(|reference| {
+            let node = nodes_borrow
+                .get(reference.node_index)
+                .expect("Got a node from a TreeNodeReference")
+                .borrow();
+            (reference.node_index, node.distance_squared_to(point))
+        })
+        .filter(|&(_, distance_squared)| distance_squared <= radius * radius)
+        .fold(
+            None,
+            |nearest: Option<(TreeNodeIndex, f64)>, candidate| match nearest {
+                Some(nearest) if nearest.1 <= candidate.1 => Some(nearest),
+                _ => Some(candidate),
+            },
+        )
+        .map(|(index, _)| {
+            let node = nodes_borrow[index].borrow();
+            NodeInfo {
+                index,
+                start: (node.start.x, node.start.y),
+                end: (node.end.x, node.end.y),
+                depth: node.depth,
+                growth_length: node.growth_length,
+                children: node.children.clone(),
+            }
+        })
+}
+
+/// Throw away all of the existing trees, and start over at the first tick with a new set of
+/// roots. The ticks of the old trees would otherwise be left behind in the timeline, before
+/// the new roots were created.
+pub fn set_roots(state: &mut State, configs: &[RootConfig]) {
+    state.nodes.borrow_mut().clear();
+    state.r_tree = RTree::new();
+    state.roots.clear();
+    state.scheduler.clear();
+    state.scheduler.reset_clock();
+    state.path_cache.clear();
+    state.stats.clear();
+    state.tick_count = 0;
+    state.playback = None;
+    state.force_redraw = true;
+    for config in configs {
+        add_root(state, config);
+    }
+}
+
+/// Grow all of the trees again from the same roots, starting over at the first tick.
+pub fn restart(state: &mut State) {
+    let configs: Vec<RootConfig> = state.roots.iter().map(|root| root.config.clone()).collect();
+    set_roots(state, &configs);
+}
 
+/// Summarize the statistics on how the trees grew.
+pub fn stats(state: &State) -> stats::Summary {
+    state.stats.summary(state.tick_count, state.r_tree.size())
+}
+
+/// Take the final statistics if the growth just completed, so that they can be handed off
+/// to JS.
+pub fn take_completion(state: &mut State) -> Option<stats::Summary> {
+    state.completion.take()
+}
+
+/// Move the camera, and redraw everything from the new point of view. The camera is
+/// validated first, so that any errors can be reported back to the caller.
+pub fn set_camera(state: &mut State, camera: Camera) -> Result<(), String> {
+    state.camera = camera.validate()?;
+    state.boundary.follow_camera(&state.camera, &state.framing);
+    state.force_redraw = true;
+    Ok(())
+}
+
+/// Change how the visualization is framed within the canvas. The growth region adapts to the
+/// current aspect ratio of the canvas.
+pub fn set_framing(state: &mut State, framing: Framing) {
+    state.framing = framing;
+    state.boundary = Boundary::new(&state.page, &state.camera, &framing);
+    state.force_redraw = true;
+}
+
+/// Change how the lines are drawn. The style is validated first, so that any errors can be
+/// reported back to the caller. A style that leaves out the transparency keeps the canvas as
+/// it was mounted.
+pub fn set_style(state: &mut State, mut style: Style) -> Result<(), String> {
+    style.resolve(Vec::new(), 0, 1.0)?;
+    let transparent = style.transparent.unwrap_or(state.page.alpha);
+    style.transparent = Some(transparent);
+    if transparent && !state.page.alpha {
+        return Err(
+            "The canvas must be mounted as transparent to use a transparent background."
+                .to_string(),
+        );
+    }
+    state.style = style;
+    clear_path_cache(state);
+    Ok(())
+}
+
+/// The tick is called for every requestAnimationFrame, with the timestamp of the frame in
+/// milliseconds. It delegates out to the update and draw calls for the visualization.
+pub fn tick(state: &mut State, timestamp: f64) {
     if state.page.is_resized {
         state.force_redraw = true;
+        if state.framing.on_resize == ResizeBehavior::Adapt {
+            state.boundary = Boundary::new(&state.page, &state.camera, &state.framing);
+        }
+    }
+
+    if state.playback.is_some() {
+        // The growth is paused while looking back through the timeline.
+        timeline::tick(state, timestamp);
+        return;
+    }
+
+    if gallery::tick(state, timestamp) {
+        return;
     }
 
+    if state.is_complete
+        && state.scheduler.is_idle()
+        && !state.force_redraw
+        && state.exporter.is_none()
+    {
+        // Nothing is growing, and nothing needs to be redrawn.
+        return;
+    }
+
+    // When exporting, the time moves forward at the frame rate of the export instead.
+    let timestamp = export::next_timestamp(state).unwrap_or(timestamp);
+
     // Update:
-    // Grow recursively grows all of the nodes.
-    base_node.borrow_mut().grow(&state.nodes, &mut state.r_tree);;
+    let steps = state.scheduler.run(
+        &state.nodes,
+        &mut state.r_tree,
+        &state.boundary,
+        &mut state.tick_count,
+        &mut state.stats,
+        &mut state.rng,
+        timestamp,
+    );
+
+    if state.style.width_by == WidthBy::SubtreeSize {
+        update_subtree_sizes(&state.nodes);
+    }
 
     // Draw:
-    draw_lines(&state);
+    let is_stale = draw_lines(state, steps);
 
-    // Reset the force_redraw.
+    // Reset the force_redraw, unless some of the lines need to be redrawn with a new width.
     state.force_redraw = false;
-    state.tick_count += 1;
+    if is_stale {
+        clear_path_cache(state);
+    }
+
+    // Only report the completion once, until something starts growing again.
+    let is_complete = state.scheduler.is_idle() && !state.roots.is_empty();
+    if is_complete && !state.is_complete {
+        state.completion = Some(stats(state));
+    }
+    state.is_complete = is_complete;
+    export::frame_drawn(state);
+}
+
+/// Throw away the cached paths, and redraw everything. This is needed when the lines that
+/// are already drawn would be drawn differently, for instance with a new style.
+pub fn clear_path_cache(state: &mut State) {
+    state.path_cache.clear();
+    for node in state.nodes.borrow().iter() {
+        let mut node = node.borrow_mut();
+        node.is_cached = false;
+        node.fully_drawn = false;
+    }
+    state.force_redraw = true;
+}
+
+/// Count the nodes in every subtree, and find the subtrees that are done growing. Children
+/// are always created after their parents, so walking backwards through the nodes means
+/// that the children are counted before their parent needs them.
+fn update_subtree_sizes(nodes: &MutableNodes) {
+    let nodes_borrow = nodes.borrow();
+    for node_cell in nodes_borrow.iter().rev() {
+        let mut node = node_cell.borrow_mut();
+        node.subtree_size = 1 + node
+            .children
+            .iter()
+            .map(|child_index| nodes_borrow[*child_index].borrow().subtree_size)
+            .sum::<u32>();
+        node.is_subtree_settled = node.is_settled()
+            && node
+                .children
+                .iter()
+                .all(|child_index| nodes_borrow[*child_index].borrow().is_subtree_settled);
+    }
 }
 
-/// Draw all of the lines.
-fn draw_lines(state: &State) {
+/// Resolve the style for drawing the lines at a given tick.
+pub fn resolve_style(state: &State, tick: u32) -> ResolvedStyle {
+    let device_pixel_ratio = state.page.device_pixel_ratio;
+    let root_colors = state
+        .roots
+        .iter()
+        .map(|root| root.config.color.clone())
+        .collect();
+    state
+        .style
+        .resolve(root_colors, tick, device_pixel_ratio)
+        .unwrap_or_else(|error| {
+            // The style is validated when it's set, so this shouldn't happen.
+            console::error_1(&error.into());
+            Style::default()
+                .resolve(Vec::new(), tick, device_pixel_ratio)
+                .expect("The default style is valid")
+        })
+}
+
+/// Clear the whole canvas to the background.
+pub fn clear_canvas(page: &PageState, style: &ResolvedStyle) {
+    if style.transparent {
+        page.ctx.clear_rect(0.0, 0.0, page.width, page.height);
+    } else {
+        page.ctx
+            .set_fill_style(&JsValue::from_str(&style.background));
+        page.ctx.fill_rect(0.0, 0.0, page.width, page.height);
+    }
+}
+
+/// Draw all of the lines, after fading out the canvas once for each of the growth steps that
+/// were just run. Returns true if some of the lines that were already drawn are out of date,
+/// and everything needs to be redrawn.
+fn draw_lines(state: &mut State, steps: u32) -> bool {
+    let view = state.view();
+    let style = resolve_style(state, state.tick_count);
     let ctx = &state.page.ctx;
 
     if state.force_redraw {
         // Only clear if we are doing a full draw.
-        ctx.set_fill_style(&JsValue::from_str("#333"));
-        ctx.fill_rect(0.0, 0.0, state.page.width, state.page.height);
+        clear_canvas(&state.page, &style);
     }
 
-    if state.tick_count < 400 {
-        if js_sys::Math::random() > 0.95 {
-            // There are only so many bits in the color representation, and this value is
-            // destructive. Only call it sparingly.
-            ctx.set_fill_style(&JsValue::from_str("#33333303"));
-        } else {
-            ctx.set_fill_style(&JsValue::from_str("#33333302"));
+    // The fade lasts for a number of ticks, so it's applied per growth step rather than per
+    // frame, and looks the same at any frame rate or speed.
+    for tick in state.tick_count + 1 - steps..state.tick_count + 1 {
+        if let Some(fade_color) = style.fade_color(tick) {
+            // This is destructive, see style::Fade.
+            ctx.set_fill_style(&JsValue::from_str(fade_color));
+            ctx.fill_rect(0.0, 0.0, state.page.width, state.page.height);
         }
-        ctx.fill_rect(0.0, 0.0, state.page.width, state.page.height);
     }
 
-    ctx.begin_path();
-    ctx.set_line_width(1.5 * state.page.device_pixel_ratio);
-    ctx.set_stroke_style(&JsValue::from_str("#fff"));
+    if state.force_redraw {
+        state.path_cache.stroke(ctx, &view);
+    }
 
-    let nodes_borrow = state.nodes.borrow();
-    let mut base_node = nodes_borrow
-        .get(0)
-        .expect("There must be at least 1 node.")
-        .borrow_mut();
+    // Collect all of the lines grouped by their color and width, so that each group only
+    // needs a single stroke.
+    let mut batches = StrokeBatches::new();
+    let mut is_stale = false;
+    for root in &state.roots {
+        let root_node = state
+            .nodes
+            .borrow()
+            .get(root.node_index)
+            .expect("A root must point to a node.")
+            .clone();
 
-    base_node.draw(&state.nodes, &state.page, state.force_redraw);
+        if root_node.borrow_mut().draw(
+            &state.nodes,
+            &mut batches,
+            &mut state.path_cache,
+            &style,
+            &view,
+            state.force_redraw,
+            None,
+        ) {
+            is_stale = true;
+        }
+    }
 
-    ctx.stroke();
+    batches.stroke(ctx);
+    is_stale
 }
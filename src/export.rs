@@ -0,0 +1,151 @@
+use draw;
+use draw::State;
+use scheduler::Budget;
+use wasm_bindgen::prelude::*;
+
+/// This module exports the growth animation as a sequence of frames, for making videos. The
+/// frames are drawn on the canvas by the usual requestAnimationFrame loop, including the
+/// fade, but the time is advanced by a fixed amount for every frame rather than following
+/// the clock, and the budget is lifted so that every growth step is run. After each frame is
+/// drawn, it's handed to a JS callback, which can capture the canvas, for instance with
+/// canvas.toBlob() for PNG frames, or a MediaRecorder on canvas.captureStream().
+
+/// The options for an export, which can come from JS.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ExportOptions {
+    /// The frames per second of the exported video.
+    pub frame_rate: f64,
+    /// How many frames to export.
+    pub frame_count: u32,
+    /// When there is a seed, the trees are grown again from their roots with the seeded
+    /// random numbers, so that the same video can be exported again.
+    pub seed: Option<u64>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> ExportOptions {
+        ExportOptions {
+            frame_rate: 30.0,
+            frame_count: 300,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Exporter {
+    options: ExportOptions,
+    callback: js_sys::Function,
+    /// The index of the next frame to draw.
+    frame_index: u32,
+    /// The frame that was drawn, and still needs to be handed to the callback.
+    drawn_frame: Option<u32>,
+    /// The budget to restore once the export is done.
+    budget: Budget,
+}
+
+/// A frame that is ready to be handed off to JS.
+pub struct ExportFrame {
+    callback: js_sys::Function,
+    index: u32,
+    count: u32,
+}
+
+impl ExportFrame {
+    /// Call the JS callback with the index of the frame, and the total number of frames.
+    /// This must be called after the State is no longer borrowed, as the callback is free
+    /// to call back into the visualization.
+    pub fn call(&self) -> Result<JsValue, JsValue> {
+        self.callback.call2(
+            &JsValue::NULL,
+            &JsValue::from_f64(self.index as f64),
+            &JsValue::from_f64(self.count as f64),
+        )
+    }
+}
+
+/// Start exporting frames, beginning with the next animation frame. The frame rate must be
+/// positive, otherwise the time would never move forward.
+pub fn start(
+    state: &mut State,
+    options: ExportOptions,
+    callback: js_sys::Function,
+) -> Result<(), String> {
+    if options.frame_rate <= 0.0 || !options.frame_rate.is_finite() {
+        return Err(format!(
+            "The frame rate must be a positive number, but it was {}.",
+            options.frame_rate
+        ));
+    }
+    if options.frame_count == 0 {
+        return Ok(());
+    }
+    if let Some(seed) = options.seed {
+        state.rng.seed(seed);
+        draw::restart(state);
+    }
+    state.playback = None;
+    state.scheduler.reset_clock();
+    state.force_redraw = true;
+
+    let budget = match state.exporter {
+        // Don't lose the original budget when an export is restarted.
+        Some(ref exporter) => exporter.budget,
+        None => state.scheduler.budget,
+    };
+    state.scheduler.budget = Budget {
+        milliseconds: std::f64::INFINITY,
+        max_steps_per_frame: std::u32::MAX,
+    };
+    state.exporter = Some(Exporter {
+        options,
+        callback,
+        frame_index: 0,
+        drawn_frame: None,
+        budget,
+    });
+    Ok(())
+}
+
+/// The timestamp of the next frame in milliseconds, if there is an export running.
+pub fn next_timestamp(state: &State) -> Option<f64> {
+    state
+        .exporter
+        .as_ref()
+        .map(|exporter| exporter.frame_index as f64 * 1000.0 / exporter.options.frame_rate)
+}
+
+/// Remember that the next frame was drawn.
+pub fn frame_drawn(state: &mut State) {
+    if let Some(ref mut exporter) = state.exporter {
+        exporter.drawn_frame = Some(exporter.frame_index);
+        exporter.frame_index += 1;
+    }
+}
+
+/// Take the frame that was just drawn, so that it can be handed off to JS. The export is
+/// finished after the last frame.
+pub fn take_frame(state: &mut State) -> Option<ExportFrame> {
+    let frame = {
+        let exporter = state.exporter.as_mut()?;
+        let index = exporter.drawn_frame.take()?;
+        ExportFrame {
+            callback: exporter.callback.clone(),
+            index,
+            count: exporter.options.frame_count,
+        }
+    };
+    if frame.index + 1 >= frame.count {
+        cancel(state);
+    }
+    Some(frame)
+}
+
+/// Stop exporting, and go back to growing the trees in real time.
+pub fn cancel(state: &mut State) {
+    if let Some(exporter) = state.exporter.take() {
+        state.scheduler.budget = exporter.budget;
+        state.scheduler.reset_clock();
+    }
+}
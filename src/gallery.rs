@@ -0,0 +1,194 @@
+use draw;
+use draw::{RootConfig, State};
+use preset;
+use stroke::StrokeBatches;
+use style::Style;
+use timeline;
+use web_sys::console;
+
+/// This module runs an attract mode for unattended displays. Every time the trees are done
+/// growing, they are held on the canvas for a while, faded out, and then grown again from
+/// scratch with a new seed. Each cycle can pick one of a list of presets at random. The
+/// nodes, the R-tree and the cached paths are all cleared between cycles, so the memory use
+/// stays flat no matter how long the gallery runs.
+
+/// The options for the gallery, which can come from JS.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GalleryOptions {
+    /// How long to hold the finished trees on the canvas, in seconds.
+    pub hold: f64,
+    /// How long it takes to fade out the trees, in seconds.
+    pub fade_out: f64,
+    /// One of these is picked at random for every cycle. When there are none, the same roots
+    /// are grown again with the current style.
+    pub presets: Vec<GalleryPreset>,
+}
+
+impl Default for GalleryOptions {
+    fn default() -> GalleryOptions {
+        GalleryOptions {
+            hold: 10.0,
+            fade_out: 2.0,
+            presets: Vec::new(),
+        }
+    }
+}
+
+/// The roots and the style to grow a cycle of the gallery with.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GalleryPreset {
+    /// The name of a preset to use the growth parameters and style of, see preset::find.
+    pub preset: Option<String>,
+    /// When there are no roots, the current ones are grown again.
+    pub roots: Vec<RootConfig>,
+    /// When there is no style, the style of the named preset or the current one is kept.
+    pub style: Option<Style>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Phase {
+    Growing,
+    /// Holding until the timestamp, in milliseconds.
+    Holding(f64),
+    /// Fading out since the timestamp, in milliseconds.
+    Fading(f64),
+}
+
+#[derive(Debug)]
+pub struct Gallery {
+    options: GalleryOptions,
+    phase: Phase,
+}
+
+/// Start cycling through the gallery, once the current trees are done growing.
+pub fn start(state: &mut State, options: GalleryOptions) {
+    state.gallery = Some(Gallery {
+        options,
+        phase: Phase::Growing,
+    });
+}
+
+/// Stop cycling, and leave the current trees on the canvas.
+pub fn stop(state: &mut State) {
+    if state.gallery.take().is_some() {
+        // The trees may have been partially faded out.
+        state.force_redraw = true;
+    }
+}
+
+/// Move the gallery along, where the timestamp is from requestAnimationFrame. Returns true
+/// if the frame was drawn by the gallery, and there is nothing left to do for it.
+pub fn tick(state: &mut State, timestamp: f64) -> bool {
+    if state.exporter.is_some() {
+        return false;
+    }
+    let phase = match state.gallery {
+        Some(ref gallery) => gallery.phase,
+        None => return false,
+    };
+    let (hold, fade_out) = {
+        let options = &state.gallery.as_ref().unwrap().options;
+        (
+            options.hold.max(0.0) * 1000.0,
+            options.fade_out.max(0.0) * 1000.0,
+        )
+    };
+
+    let next_phase = match phase {
+        Phase::Growing if state.is_complete => Phase::Holding(timestamp + hold),
+        // Something started growing again, such as a root that was added while holding.
+        Phase::Holding(_) if !state.is_complete => Phase::Growing,
+        Phase::Holding(until) if timestamp >= until => Phase::Fading(timestamp),
+        Phase::Fading(since) => {
+            let t = if fade_out == 0.0 {
+                1.0
+            } else {
+                (timestamp - since) / fade_out
+            };
+            if t < 1.0 {
+                draw_faded(state, 1.0 - t);
+                return true;
+            }
+            next_cycle(state);
+            Phase::Growing
+        }
+        phase => phase,
+    };
+    if let Some(ref mut gallery) = state.gallery {
+        gallery.phase = next_phase;
+    }
+    false
+}
+
+/// Draw the finished trees, partially faded out to the background. Not every line ends up in
+/// the path cache, e.g. the ones colored by age, so the full trees are drawn each time.
+fn draw_faded(state: &State, opacity: f64) {
+    let style = draw::resolve_style(state, state.tick_count);
+    draw::clear_canvas(&state.page, &style);
+    let mut batches = StrokeBatches::new();
+    timeline::add_trees_at_tick(
+        state,
+        &mut batches,
+        &style,
+        &state.view(),
+        state.tick_count as f64,
+    );
+    state.page.ctx.set_global_alpha(opacity.max(0.0).min(1.0));
+    batches.stroke(&state.page.ctx);
+    state.page.ctx.set_global_alpha(1.0);
+}
+
+/// Grow a new set of trees with a new seed, from a random preset.
+fn next_cycle(state: &mut State) {
+    state.rng.reseed();
+    let gallery_preset = {
+        let presets = &state.gallery.as_ref().unwrap().options.presets;
+        if presets.is_empty() {
+            None
+        } else {
+            let index = (state.rng.random() * presets.len() as f64) as usize;
+            Some(presets[index.min(presets.len() - 1)].clone())
+        }
+    };
+
+    let mut configs: Vec<RootConfig> = state.roots.iter().map(|root| root.config.clone()).collect();
+    let gallery_preset = match gallery_preset {
+        Some(gallery_preset) => gallery_preset,
+        None => {
+            draw::set_roots(state, &configs);
+            return;
+        }
+    };
+    if !gallery_preset.roots.is_empty() {
+        configs = gallery_preset.roots;
+    }
+    let named_preset = gallery_preset.preset.and_then(|name| {
+        let named_preset = preset::find(&name);
+        if named_preset.is_none() {
+            console::error_1(&format!("Unknown preset \"{}\"", name).into());
+        }
+        named_preset
+    });
+
+    let result = match named_preset {
+        Some(mut named_preset) => {
+            if let Some(style) = gallery_preset.style {
+                named_preset.style = style;
+            }
+            preset::apply(state, &named_preset, &configs)
+        }
+        None => {
+            let result = match gallery_preset.style {
+                Some(style) => draw::set_style(state, style),
+                None => Ok(()),
+            };
+            draw::set_roots(state, &configs);
+            result
+        }
+    };
+    if let Err(error) = result {
+        console::error_1(&error.into());
+    }
+}
@@ -0,0 +1,193 @@
+use cgmath::prelude::*;
+use cgmath::Vector2;
+use draw::State;
+use plot;
+use plot::Polyline;
+use std::fmt::Write;
+use tree_node::TreeNodeIndex;
+
+/// This module writes the trees out as G-code, for cutting or engraving them on a CNC
+/// machine or a laser. The connected lines are chained into polylines and ordered in the
+/// same way as for a pen plotter. The trees are placed exactly as they are framed on the
+/// canvas, at a physical scale of millimeters per CSS pixel, with the origin in the bottom
+/// left corner of the canvas. The power can change with the depth of the branches, so the
+/// trunks can be cut deeper or burned darker than the tips.
+
+/// The options for the G-code, which can come from JS. The distances are in millimeters.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GcodeOptions {
+    /// How many millimeters a CSS pixel on the canvas takes up.
+    pub scale: f64,
+    /// The feed rate while cutting, in millimeters per minute.
+    pub feed_rate: f64,
+    /// The spindle speed or laser power, as [trunk, tips]. The power is interpolated
+    /// between these two based on the depth of each branch.
+    pub power: (f64, f64),
+    /// The height to travel at between the polylines.
+    pub z_up: f64,
+    /// The height to cut at.
+    pub z_down: f64,
+    /// Order the polylines to keep the travel short.
+    pub optimize: bool,
+}
+
+impl Default for GcodeOptions {
+    fn default() -> GcodeOptions {
+        GcodeOptions {
+            scale: 0.25,
+            feed_rate: 1000.0,
+            power: (1000.0, 1000.0),
+            z_up: 5.0,
+            z_down: -1.0,
+            optimize: true,
+        }
+    }
+}
+
+/// A summary of the toolpath, so that the dimensions can be checked before cutting.
+#[derive(Clone, Debug, Serialize)]
+pub struct GcodeSummary {
+    /// The [x, y] bounds of everything that is cut.
+    pub min: (f64, f64),
+    pub max: (f64, f64),
+    pub width: f64,
+    pub height: f64,
+    /// The size of the whole canvas at this scale.
+    pub work_area: (f64, f64),
+    pub polyline_count: usize,
+    pub cut_length: f64,
+    pub travel_length: f64,
+    /// The time spent cutting at the feed rate. This doesn't include the travel.
+    pub cut_minutes: f64,
+}
+
+/// Write the G-code for the trees.
+pub fn gcode(state: &State, options: &GcodeOptions) -> String {
+    let polylines = toolpath(state, options);
+    let summary = summarize(state, options, &polylines);
+    let nodes = state.nodes.borrow();
+
+    let mut gcode = String::new();
+    writeln!(
+        gcode,
+        "; Bounds X{:.3}..{:.3} Y{:.3}..{:.3}, {:.3} x {:.3} mm",
+        summary.min.0, summary.max.0, summary.min.1, summary.max.1, summary.width, summary.height
+    )
+    .unwrap();
+    writeln!(gcode, "G21 ; Millimeters").unwrap();
+    writeln!(gcode, "G90 ; Absolute positioning").unwrap();
+    writeln!(gcode, "G0 Z{:.3}", options.z_up).unwrap();
+
+    let power_at = |node_index: TreeNodeIndex| {
+        let node = nodes[node_index].borrow();
+        let t = (node.depth as f64 / node.max_tree_depth.max(1) as f64)
+            .max(0.0)
+            .min(1.0);
+        (options.power.0 + (options.power.1 - options.power.0) * t).round()
+    };
+    for polyline in &polylines {
+        let start = polyline.start();
+        writeln!(gcode, "G0 X{:.3} Y{:.3}", start.x, start.y).unwrap();
+        // Start the spindle or laser at the power of the first line before plunging.
+        let mut current_power = power_at(polyline.nodes[0]);
+        writeln!(gcode, "M3 S{:.0}", current_power).unwrap();
+        writeln!(gcode, "G1 Z{:.3} F{:.0}", options.z_down, options.feed_rate).unwrap();
+        for (point, &node_index) in polyline.points[1..].iter().zip(&polyline.nodes) {
+            let power = power_at(node_index);
+            if power != current_power {
+                writeln!(gcode, "G1 X{:.3} Y{:.3} S{:.0}", point.x, point.y, power).unwrap();
+                current_power = power;
+            } else {
+                writeln!(gcode, "G1 X{:.3} Y{:.3}", point.x, point.y).unwrap();
+            }
+        }
+        writeln!(gcode, "G0 Z{:.3}", options.z_up).unwrap();
+    }
+
+    writeln!(gcode, "M5").unwrap();
+    writeln!(gcode, "G0 X0 Y0").unwrap();
+    gcode
+}
+
+/// Summarize the toolpath without writing out the G-code, as a dry run.
+pub fn dry_run(state: &State, options: &GcodeOptions) -> GcodeSummary {
+    let polylines = toolpath(state, options);
+    summarize(state, options, &polylines)
+}
+
+/// The polylines in millimeters, clipped to the canvas, and in the order they are cut.
+fn toolpath(state: &State, options: &GcodeOptions) -> Vec<Polyline> {
+    let view = state.view();
+    // Go from canvas device pixels to millimeters, and flip the y axis so that it goes up.
+    let mm_per_pixel = options.scale / state.page.device_pixel_ratio;
+    let page_height = state.page.height;
+    let polylines = plot::build_polylines(state)
+        .into_iter()
+        .map(|polyline| Polyline {
+            points: polyline
+                .points
+                .iter()
+                .map(|&point| {
+                    let point = view.unit_to_page(point);
+                    Vector2::new(point.x, page_height - point.y) * mm_per_pixel
+                })
+                .collect(),
+            nodes: polyline.nodes,
+        })
+        .collect();
+    let (width, height) = work_area(state, options);
+    let polylines = plot::clip_polylines(
+        polylines,
+        Vector2::new(0.0, 0.0),
+        Vector2::new(width, height),
+    );
+    if options.optimize {
+        plot::order_polylines(polylines, Vector2::new(0.0, 0.0))
+    } else {
+        polylines
+    }
+}
+
+/// The size of the canvas in millimeters.
+fn work_area(state: &State, options: &GcodeOptions) -> (f64, f64) {
+    let mm_per_pixel = options.scale / state.page.device_pixel_ratio;
+    (
+        state.page.width * mm_per_pixel,
+        state.page.height * mm_per_pixel,
+    )
+}
+
+fn summarize(state: &State, options: &GcodeOptions, polylines: &[Polyline]) -> GcodeSummary {
+    let mut min = Vector2::new(std::f64::INFINITY, std::f64::INFINITY);
+    let mut max = Vector2::new(std::f64::NEG_INFINITY, std::f64::NEG_INFINITY);
+    let mut cut_length = 0.0;
+    for polyline in polylines {
+        for point in &polyline.points {
+            min = Vector2::new(min.x.min(point.x), min.y.min(point.y));
+            max = Vector2::new(max.x.max(point.x), max.y.max(point.y));
+        }
+        for pair in polyline.points.windows(2) {
+            cut_length += (pair[1] - pair[0]).magnitude();
+        }
+    }
+    if polylines.is_empty() {
+        min = Vector2::new(0.0, 0.0);
+        max = Vector2::new(0.0, 0.0);
+    }
+    GcodeSummary {
+        min: (min.x, min.y),
+        max: (max.x, max.y),
+        width: max.x - min.x,
+        height: max.y - min.y,
+        work_area: work_area(state, options),
+        polyline_count: polylines.len(),
+        cut_length,
+        travel_length: plot::travel_distance(polylines, Vector2::new(0.0, 0.0)),
+        cut_minutes: if options.feed_rate > 0.0 {
+            cut_length / options.feed_rate
+        } else {
+            0.0
+        },
+    }
+}
@@ -0,0 +1,176 @@
+use cgmath::prelude::*;
+use cgmath::Vector2;
+use dom;
+use draw;
+use draw::State;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// This module turns the pointer and wheel input on the canvas into changes to the
+/// visualization. A click seeds a new tree, a drag pans the camera, and a drag while holding
+/// shift seeds a tree that grows in the direction of the drag. The wheel zooms the camera.
+/// The input can either come straight from the DOM events, or be forwarded to a worker.
+
+/// Keep track of a pointer from the moment it's pressed. All positions are in canvas device
+/// pixel space.
+struct PointerDrag {
+    down: Vector2<f64>,
+    last: Vector2<f64>,
+    is_seeding: bool,
+    is_panning: bool,
+}
+
+#[derive(Default)]
+pub struct PointerInput {
+    drag: Option<PointerDrag>,
+}
+
+impl PointerInput {
+    pub fn pointer_down(&mut self, position: Vector2<f64>, shift_key: bool) {
+        self.drag = Some(PointerDrag {
+            down: position,
+            last: position,
+            is_seeding: shift_key,
+            is_panning: false,
+        });
+    }
+
+    /// The buttons are the buttons that are currently pressed, from MouseEvent.buttons. If the
+    /// pointer was released without an up event reaching the canvas, the drag is dropped.
+    pub fn pointer_move(&mut self, state: &mut State, position: Vector2<f64>, buttons: u16) {
+        if buttons == 0 {
+            self.drag = None;
+        }
+        if let Some(ref mut drag) = self.drag {
+            if drag.is_seeding {
+                return;
+            }
+            let threshold = draw::DRAG_THRESHOLD * state.page.device_pixel_ratio;
+            if !drag.is_panning && (position - drag.down).magnitude() < threshold {
+                return;
+            }
+            drag.is_panning = true;
+            let last = drag.last;
+            drag.last = position;
+            let mut camera = state.camera;
+            camera.pan(&state.page, &state.framing, last, position);
+            // An empty canvas can't be mapped back into the unit interval space, in which
+            // case the camera stays where it was.
+            let _ = draw::set_camera(state, camera);
+        }
+    }
+
+    pub fn pointer_up(&mut self, state: &mut State, position: Vector2<f64>) {
+        if let Some(drag) = self.drag.take() {
+            if drag.is_panning {
+                return;
+            }
+            let up = if drag.is_seeding { position } else { drag.down };
+            draw::add_root_from_pointer(state, (drag.down.x, drag.down.y), (up.x, up.y));
+        }
+    }
+
+    pub fn pointer_cancel(&mut self) {
+        self.drag = None;
+    }
+
+    /// Zoom in or out around the position, where the delta is in pixels.
+    pub fn wheel(&mut self, state: &mut State, position: Vector2<f64>, delta: f64) {
+        let mut camera = state.camera;
+        camera.zoom_at(
+            &state.page,
+            &state.framing,
+            position,
+            (-delta * 0.002).exp(),
+        );
+        let _ = draw::set_camera(state, camera);
+    }
+}
+
+/// The position of a pointer or wheel event in canvas device pixel space.
+pub fn event_position(
+    canvas: &web_sys::HtmlCanvasElement,
+    event: &web_sys::MouseEvent,
+) -> Vector2<f64> {
+    let (x, y) = dom::canvas_device_pixel_position(canvas, event);
+    Vector2::new(x, y)
+}
+
+/// The wheel delta is either in pixels, or in lines, so normalize it to pixels.
+pub fn wheel_delta(event: &web_sys::WheelEvent) -> f64 {
+    match event.delta_mode() {
+        0 => event.delta_y(),
+        _ => event.delta_y() * 16.0,
+    }
+}
+
+/// Listen to the pointer and wheel events on the canvas, until the returned listeners are
+/// dropped.
+pub fn listen_to_canvas(
+    canvas: &web_sys::HtmlCanvasElement,
+    state: Rc<RefCell<State>>,
+) -> Vec<dom::EventListener> {
+    let input = Rc::new(RefCell::new(PointerInput::default()));
+
+    let pointer_down = dom::on_canvas_pointer_event(canvas, "pointerdown", {
+        let input = input.clone();
+        let canvas = canvas.clone();
+        move |event| {
+            dom::capture_pointer(&canvas, &event);
+            input
+                .borrow_mut()
+                .pointer_down(event_position(&canvas, &event), event.shift_key());
+        }
+    });
+
+    let pointer_move = dom::on_canvas_pointer_event(canvas, "pointermove", {
+        let input = input.clone();
+        let canvas = canvas.clone();
+        let state = state.clone();
+        move |event| {
+            input.borrow_mut().pointer_move(
+                &mut state.borrow_mut(),
+                event_position(&canvas, &event),
+                event.buttons(),
+            );
+        }
+    });
+
+    let pointer_cancel = dom::on_canvas_pointer_event(canvas, "pointercancel", {
+        let input = input.clone();
+        move |_| {
+            input.borrow_mut().pointer_cancel();
+        }
+    });
+
+    let pointer_up = dom::on_canvas_pointer_event(canvas, "pointerup", {
+        let input = input.clone();
+        let canvas = canvas.clone();
+        let state = state.clone();
+        move |event| {
+            input
+                .borrow_mut()
+                .pointer_up(&mut state.borrow_mut(), event_position(&canvas, &event));
+        }
+    });
+
+    let wheel = dom::on_canvas_wheel_event(canvas, {
+        let canvas = canvas.clone();
+        move |event| {
+            event.prevent_default();
+            input.borrow_mut().wheel(
+                &mut state.borrow_mut(),
+                event_position(&canvas, &event),
+                wheel_delta(&event),
+            );
+        }
+    });
+
+    vec![
+        pointer_down,
+        pointer_move,
+        pointer_cancel,
+        pointer_up,
+        wheel,
+    ]
+}
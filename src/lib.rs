@@ -1,23 +1,92 @@
 pub mod dom;
 pub mod draw;
+pub mod export;
+pub mod gallery;
+pub mod gcode;
+pub mod input;
+pub mod pdf;
+pub mod plot;
+pub mod preset;
+pub mod random;
+pub mod scheduler;
+pub mod stats;
+pub mod stroke;
+pub mod style;
+pub mod timeline;
+pub mod tree_canvas;
 pub mod tree_node;
 pub mod tree_node_ref;
+pub mod view;
+pub mod worker;
 
 #[macro_use]
 extern crate serde_derive;
 extern crate cgmath;
 extern crate console_error_panic_hook;
 extern crate js_sys;
+extern crate serde;
 extern crate spade;
 extern crate wasm_bindgen;
 extern crate web_sys;
 
 use std::cell::RefCell;
 use std::panic;
-use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use web_sys::console;
 
+thread_local! {
+    /// The canvas that is mounted automatically when the page has a #canvas element.
+    static DEFAULT_CANVAS: RefCell<Option<tree_canvas::TreeCanvas>> = RefCell::new(None);
+}
+
+/// Get the canvas that was mounted automatically onto the #canvas element.
+#[wasm_bindgen]
+pub fn default_canvas() -> Result<tree_canvas::TreeCanvas, JsValue> {
+    DEFAULT_CANVAS.with(|default_canvas| {
+        default_canvas
+            .borrow()
+            .clone()
+            .ok_or_else(|| JsValue::from_str("There is no #canvas element on the page."))
+    })
+}
+
+/// Seed a new independent tree on the default canvas. The config is an object matching
+/// draw::RootConfig, where any missing values are filled in with the defaults.
+#[wasm_bindgen]
+pub fn add_root(config: JsValue) -> Result<(), JsValue> {
+    default_canvas()?.add_root(config)
+}
+
+/// Replace all of the trees on the default canvas with a new set of roots, from an array of
+/// root configs.
+#[wasm_bindgen]
+pub fn set_roots(configs: JsValue) -> Result<(), JsValue> {
+    default_canvas()?.set_roots(configs)
+}
+
+/// Change the camera of the default canvas, from an object matching view::Camera. Any
+/// missing values are filled in with the defaults, so calling this with an empty object
+/// resets the camera.
+#[wasm_bindgen]
+pub fn set_camera(camera: JsValue) -> Result<(), JsValue> {
+    default_canvas()?.set_camera(camera)
+}
+
+/// Change how the lines are drawn on the default canvas, from an object matching
+/// style::Style. Any missing values are filled in with the defaults.
+#[wasm_bindgen]
+pub fn set_style(style: JsValue) -> Result<(), JsValue> {
+    default_canvas()?.set_style(style)
+}
+
+/// Look up the node under a point on the default canvas. The point is in CSS pixels relative
+/// to the canvas, such as the offsetX and offsetY of a mouse event. Returns null if there is
+/// no node near the point.
+#[wasm_bindgen]
+pub fn node_at(x: f64, y: f64) -> Result<JsValue, JsValue> {
+    Ok(default_canvas()?.node_at(x, y))
+}
+
 /// This module handles the high-level initialization.
 
 #[wasm_bindgen(start)]
@@ -25,43 +94,29 @@ pub fn run() -> Result<(), JsValue> {
     panic::set_hook(Box::new(console_error_panic_hook::hook));
     console::log_1(&"Started executing the wasm code.".into());
 
-    // Track page resizing across callback.
-    let page_is_resized = Rc::new(RefCell::new(true));
+    // The same module is loaded inside of the worker, where there is no page to mount on.
+    if web_sys::window().is_none() {
+        return Ok(());
+    }
 
-    // Handle window resizes.
-    dom::on_window_resize({
-        let page_is_resized_refcell = page_is_resized.clone();
-        // Set the canvas size and then add an event listener to resize on window resize.
-        dom::set_canvas_to_window_size();
-        move || {
-            dom::set_canvas_to_window_size();
-            *page_is_resized_refcell.borrow_mut() = true;
-        }
-    });
-
-    dom::start_raf({
-        let mut state = draw::init(dom::PageState {
-            width: dom::window_device_pixel_width(),
-            height: dom::window_device_pixel_height(),
-            device_pixel_ratio: dom::window().device_pixel_ratio(),
-            is_resized: true,
-            ctx: dom::get_context(),
-        });
-
-        move || {
-            let is_resized = page_is_resized.borrow().clone();
-            if is_resized {
-                state.page.width = dom::window_device_pixel_width();
-                state.page.height = dom::window_device_pixel_height();
-                state.page.device_pixel_ratio = dom::window().device_pixel_ratio();
-                state.page.is_resized = true;
-                *page_is_resized.borrow_mut() = false;
+    // Mount onto the #canvas element if there is one, otherwise it's up to JS to create
+    // a TreeCanvas.
+    if let Ok(canvas) = dom::query_canvas("#canvas") {
+        let options = tree_canvas::MountOptions {
+            transparent: dom::canvas_is_transparent(&canvas),
+            ..tree_canvas::MountOptions::default()
+        };
+        match dom::canvas_worker_url(&canvas) {
+            Some(worker_url) => {
+                worker::mount(canvas, &worker_url, &options)?;
+            }
+            None => {
+                let tree_canvas = tree_canvas::TreeCanvas::mount(canvas, &options);
+                DEFAULT_CANVAS
+                    .with(|default_canvas| *default_canvas.borrow_mut() = Some(tree_canvas));
             }
-
-            draw::tick(&mut state);
-            state.page.is_resized = false;
         }
-    });
+    }
 
     Ok(())
 }
@@ -0,0 +1,206 @@
+use cgmath::Vector2;
+use draw;
+use draw::State;
+use std::fmt::Write;
+use stroke::{PathCommand, StrokeBatches};
+use style;
+use timeline;
+use view::ViewTransform;
+
+/// This module writes the trees out as a vector PDF for print. It's a minimal PDF writer,
+/// with a single uncompressed page that strokes the same paths as the canvas, with the same
+/// colors and line widths. The page is the trim size, with the bleed added around it, and
+/// the background is printed out into the bleed. The trees are framed within the margin in
+/// the same way as they are on the canvas, and anything outside of the margin is clipped.
+
+/// The options for a PDF, which can come from JS. All of the sizes are in millimeters.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PdfOptions {
+    /// The [width, height] of the page once it's trimmed.
+    pub page_size: (f64, f64),
+    /// How far the background extends past the trimmed page on every side.
+    pub bleed: f64,
+    /// The distance from the trimmed edge of the page to the trees.
+    pub margin: f64,
+}
+
+impl Default for PdfOptions {
+    fn default() -> PdfOptions {
+        PdfOptions {
+            // A4 paper.
+            page_size: (210.0, 297.0),
+            bleed: 3.0,
+            margin: 15.0,
+        }
+    }
+}
+
+/// PDFs are measured in points, which are 1/72 of an inch.
+const POINTS_PER_MM: f64 = 72.0 / 25.4;
+
+/// The line widths are in CSS pixels, which are 1/96 of an inch.
+const POINTS_PER_CSS_PIXEL: f64 = 72.0 / 96.0;
+
+/// Create the PDF. It only contains ASCII, so it's returned as a string.
+pub fn pdf(state: &State, options: &PdfOptions) -> String {
+    let bleed = options.bleed.max(0.0) * POINTS_PER_MM;
+    let margin = options.margin.max(0.0) * POINTS_PER_MM;
+    let trim_width = options.page_size.0.max(0.0) * POINTS_PER_MM;
+    let trim_height = options.page_size.1.max(0.0) * POINTS_PER_MM;
+    let media_width = trim_width + bleed * 2.0;
+    let media_height = trim_height + bleed * 2.0;
+    let width = (trim_width - margin * 2.0).max(0.0);
+    let height = (trim_height - margin * 2.0).max(0.0);
+
+    let view = ViewTransform::with_size(width, height, &state.camera, &state.framing);
+    let style = draw::resolve_style(state, state.tick_count);
+    let mut batches = StrokeBatches::new();
+    timeline::add_trees_at_tick(state, &mut batches, &style, &view, state.tick_count as f64);
+
+    let mut content = String::new();
+    if !style.transparent {
+        let [r, g, b] = rgb(&style.background);
+        writeln!(content, "{:.4} {:.4} {:.4} rg", r, g, b).unwrap();
+        writeln!(content, "0 0 {:.3} {:.3} re f", media_width, media_height).unwrap();
+    }
+    // Flip the y axis so that the origin is in the top left corner of the margin, like the
+    // canvas, and clip to the margin.
+    writeln!(
+        content,
+        "1 0 0 -1 {:.3} {:.3} cm",
+        bleed + margin,
+        media_height - bleed - margin
+    )
+    .unwrap();
+    writeln!(content, "0 0 {:.3} {:.3} re W n", width, height).unwrap();
+
+    let device_pixel_ratio = state.page.device_pixel_ratio;
+    for (stroke_style, commands) in batches.batches() {
+        let [r, g, b] = rgb(stroke_style.color);
+        writeln!(content, "{:.4} {:.4} {:.4} RG", r, g, b).unwrap();
+        writeln!(
+            content,
+            "{:.3} w",
+            stroke_style.width as f64 / 4.0 / device_pixel_ratio * POINTS_PER_CSS_PIXEL
+        )
+        .unwrap();
+        write_path(&mut content, commands);
+        writeln!(content, "S").unwrap();
+    }
+
+    write_document(&[
+        String::from("<< /Type /Catalog /Pages 2 0 R >>"),
+        String::from("<< /Type /Pages /Kids [3 0 R] /Count 1 >>"),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.3} {:.3}] \
+                 /BleedBox [0 0 {:.3} {:.3}] /TrimBox [{:.3} {:.3} {:.3} {:.3}] \
+                 /Resources << >> /Contents 4 0 R >>",
+            media_width,
+            media_height,
+            media_width,
+            media_height,
+            bleed,
+            bleed,
+            bleed + trim_width,
+            bleed + trim_height
+        ),
+        format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            content.len(),
+            content
+        ),
+    ])
+}
+
+/// The RGB values of a hex color, from 0 to 1. The root colors aren't validated, so fall
+/// back to white in the same way as ResolvedStyle::color.
+fn rgb(color: &str) -> [f64; 3] {
+    let [r, g, b] = style::parse_hex(color).unwrap_or([255.0, 255.0, 255.0]);
+    [r / 255.0, g / 255.0, b / 255.0]
+}
+
+/// Write the path operators. PDFs only have cubic curves, so the quadratic curves are
+/// raised to cubic ones.
+fn write_path(content: &mut String, commands: &[PathCommand]) {
+    let mut current = Vector2::new(0.0, 0.0);
+    for command in commands {
+        match *command {
+            PathCommand::MoveTo(point) => {
+                writeln!(content, "{:.3} {:.3} m", point.x, point.y).unwrap();
+                current = point;
+            }
+            PathCommand::LineTo(point) => {
+                writeln!(content, "{:.3} {:.3} l", point.x, point.y).unwrap();
+                current = point;
+            }
+            PathCommand::QuadraticCurveTo(control, point) => {
+                let control1 = current + (control - current) * (2.0 / 3.0);
+                let control2 = point + (control - point) * (2.0 / 3.0);
+                writeln!(
+                    content,
+                    "{:.3} {:.3} {:.3} {:.3} {:.3} {:.3} c",
+                    control1.x, control1.y, control2.x, control2.y, point.x, point.y
+                )
+                .unwrap();
+                current = point;
+            }
+        }
+    }
+}
+
+/// Number the objects starting from 1, and write them out along with the cross-reference
+/// table of their byte offsets. The first object must be the catalog.
+fn write_document(objects: &[String]) -> String {
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        writeln!(pdf, "{} 0 obj\n{}\nendobj", index + 1, object).unwrap();
+    }
+
+    let xref_offset = pdf.len();
+    writeln!(pdf, "xref\n0 {}", objects.len() + 1).unwrap();
+    // Every entry is exactly 20 bytes, including the two character end of line.
+    pdf.push_str("0000000000 65535 f \n");
+    for offset in offsets {
+        writeln!(pdf, "{:010} 00000 n ", offset).unwrap();
+    }
+    writeln!(
+        pdf,
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF",
+        objects.len() + 1,
+        xref_offset
+    )
+    .unwrap();
+    pdf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xref_offsets_point_at_the_objects() {
+        let objects = vec![
+            String::from("<< /Type /Catalog /Pages 2 0 R >>"),
+            String::from("<< /Type /Pages /Kids [] /Count 0 >>"),
+            String::from("<< /Length 6 >>\nstream\n0 0 m\nendstream"),
+        ];
+        let pdf = write_document(&objects);
+
+        let startxref = pdf.rfind("startxref\n").unwrap() + "startxref\n".len();
+        let xref_offset: usize = pdf[startxref..].lines().next().unwrap().parse().unwrap();
+        assert!(pdf[xref_offset..].starts_with("xref\n0 4\n"));
+
+        let entries: Vec<&str> = pdf[xref_offset..].lines().skip(2).take(4).collect();
+        assert_eq!(entries[0], "0000000000 65535 f ");
+        for (index, entry) in entries[1..].iter().enumerate() {
+            // Every entry is 20 bytes including the line ending.
+            assert_eq!(entry.len() + 1, 20);
+            let offset: usize = entry[0..10].parse().unwrap();
+            let header = format!("{} 0 obj\n", index + 1);
+            assert!(pdf[offset..].starts_with(&header), "{}", header);
+        }
+    }
+}
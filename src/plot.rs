@@ -0,0 +1,475 @@
+use cgmath::prelude::*;
+use cgmath::{Point2, Vector2};
+use draw::State;
+use spade::rtree::RTree;
+use spade::{BoundingRect, SpatialObject};
+use std::fmt::Write;
+use tree_node::TreeNodeIndex;
+use view::ViewTransform;
+
+/// This module turns the trees into output for a pen plotter. Drawing every node as its own
+/// line would lift and drop the pen thousands of times, so the connected lines are chained
+/// together into long polylines first. A polyline follows the first child of every node,
+/// and the other children start polylines of their own. The polylines are then ordered to
+/// keep the distance traveled with the pen up short, first by greedily picking the nearest
+/// polyline, and then improving the order with 2-opt. The output is either SVG or HPGL, laid
+/// out on a physical page in millimeters.
+
+/// A chain of connected lines.
+#[derive(Clone, Debug)]
+pub struct Polyline {
+    pub points: Vec<Vector2<f64>>,
+    /// The node that each line between two points came from, so there is one less node than
+    /// there are points.
+    pub nodes: Vec<TreeNodeIndex>,
+}
+
+impl Polyline {
+    pub fn start(&self) -> Vector2<f64> {
+        self.points[0]
+    }
+
+    pub fn end(&self) -> Vector2<f64> {
+        self.points[self.points.len() - 1]
+    }
+
+    /// Draw the polyline in the opposite direction.
+    pub fn reverse(&mut self) {
+        self.points.reverse();
+        self.nodes.reverse();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlotFormat {
+    Svg,
+    Hpgl,
+}
+
+/// The options for a plot, which can come from JS. All of the sizes are in millimeters.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PlotOptions {
+    pub format: PlotFormat,
+    /// The [width, height] of the paper.
+    pub page_size: (f64, f64),
+    /// The trees are framed within the page inside of the margin, and anything outside of it
+    /// is clipped.
+    pub margin: f64,
+    /// The width of the pen, which is only used for the SVG.
+    pub stroke_width: f64,
+    /// Order the polylines to keep the pen-up travel short.
+    pub optimize: bool,
+}
+
+impl Default for PlotOptions {
+    fn default() -> PlotOptions {
+        PlotOptions {
+            format: PlotFormat::Svg,
+            // A4 paper.
+            page_size: (210.0, 297.0),
+            margin: 15.0,
+            stroke_width: 0.3,
+            optimize: true,
+        }
+    }
+}
+
+/// HPGL plotters use units of 0.025mm.
+const HPGL_UNITS_PER_MM: f64 = 40.0;
+
+/// The most passes of 2-opt to run.
+const MAX_TWO_OPT_PASSES: usize = 8;
+
+/// The longest run of polylines that 2-opt tries reversing, so that a pass is O(n) rather
+/// than O(n^2). The nearest neighbor order already keeps nearby polylines close together in
+/// the order, so the long runs rarely help.
+const TWO_OPT_WINDOW: usize = 100;
+
+/// Create the plot in the chosen format.
+pub fn plot(state: &State, options: &PlotOptions) -> String {
+    let mut polylines = page_polylines(state, options.page_size, options.margin);
+    if options.optimize {
+        // The pen starts out in the corner of the page.
+        polylines = order_polylines(polylines, Vector2::new(0.0, 0.0));
+    }
+    match options.format {
+        PlotFormat::Svg => to_svg(&polylines, options),
+        PlotFormat::Hpgl => to_hpgl(&polylines, options),
+    }
+}
+
+/// Chain all of the nodes into polylines, in the unit interval space. The lines are exactly
+/// the ones that are drawn, so a node that is still growing is cut short.
+pub fn build_polylines(state: &State) -> Vec<Polyline> {
+    let nodes = state.nodes.borrow();
+    let mut polylines = Vec::new();
+    let mut starts: Vec<TreeNodeIndex> = state
+        .roots
+        .iter()
+        .rev()
+        .map(|root| root.node_index)
+        .collect();
+
+    while let Some(start_index) = starts.pop() {
+        let mut polyline = Polyline {
+            points: vec![nodes[start_index].borrow().start],
+            nodes: Vec::new(),
+        };
+        let mut node_index = start_index;
+        loop {
+            let node = nodes[node_index].borrow();
+            if node.growth_length == 0.0 {
+                break;
+            }
+            polyline.points.push(node.end_at(node.growth_length));
+            polyline.nodes.push(node_index);
+
+            let mut children = node
+                .children
+                .iter()
+                .cloned()
+                .filter(|&child_index| nodes[child_index].borrow().growth_length > 0.0);
+            match children.next() {
+                Some(child_index) => {
+                    starts.extend(children);
+                    node_index = child_index;
+                }
+                None => break,
+            }
+        }
+        if !polyline.nodes.is_empty() {
+            polylines.push(polyline);
+        }
+    }
+    polylines
+}
+
+/// The polylines laid out on a page, using the same framing as the canvas. The points are
+/// in the same units as the page size, with the origin in the top left corner.
+pub fn page_polylines(state: &State, page_size: (f64, f64), margin: f64) -> Vec<Polyline> {
+    let width = (page_size.0 - margin * 2.0).max(0.0);
+    let height = (page_size.1 - margin * 2.0).max(0.0);
+    let view = ViewTransform::with_size(width, height, &state.camera, &state.framing);
+    let offset = Vector2::new(margin, margin);
+    let polylines = build_polylines(state)
+        .into_iter()
+        .map(|polyline| Polyline {
+            points: polyline
+                .points
+                .iter()
+                .map(|&point| view.unit_to_page(point) + offset)
+                .collect(),
+            nodes: polyline.nodes,
+        })
+        .collect();
+    clip_polylines(polylines, offset, offset + Vector2::new(width, height))
+}
+
+/// Cut the polylines at the edges of a rectangle, and drop the parts outside of it.
+pub fn clip_polylines(
+    polylines: Vec<Polyline>,
+    min: Vector2<f64>,
+    max: Vector2<f64>,
+) -> Vec<Polyline> {
+    let mut clipped = Vec::new();
+    for polyline in polylines {
+        let mut current: Option<Polyline> = None;
+        for (i, &node_index) in polyline.nodes.iter().enumerate() {
+            let from = polyline.points[i];
+            let to = polyline.points[i + 1];
+            match clip_line(from, to, min, max) {
+                Some((t0, t1)) => {
+                    let line = to - from;
+                    if t0 > 0.0 || current.is_none() {
+                        clipped.extend(current.take());
+                        current = Some(Polyline {
+                            points: vec![from + line * t0],
+                            nodes: Vec::new(),
+                        });
+                    }
+                    if let Some(ref mut current) = current {
+                        current.points.push(from + line * t1);
+                        current.nodes.push(node_index);
+                    }
+                    if t1 < 1.0 {
+                        clipped.extend(current.take());
+                    }
+                }
+                None => clipped.extend(current.take()),
+            }
+        }
+        clipped.extend(current.take());
+    }
+    clipped
+}
+
+/// Clip a line to a rectangle with the Liang-Barsky algorithm. Returns the range of the line
+/// that is inside, from 0.0 at the start, to 1.0 at the end.
+fn clip_line(
+    from: Vector2<f64>,
+    to: Vector2<f64>,
+    min: Vector2<f64>,
+    max: Vector2<f64>,
+) -> Option<(f64, f64)> {
+    let delta = to - from;
+    let mut t0: f64 = 0.0;
+    let mut t1: f64 = 1.0;
+    let edges = [
+        (-delta.x, from.x - min.x),
+        (delta.x, max.x - from.x),
+        (-delta.y, from.y - min.y),
+        (delta.y, max.y - from.y),
+    ];
+    for &(p, q) in &edges {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t0 = t0.max(t);
+            } else {
+                t1 = t1.min(t);
+            }
+        }
+    }
+    if t0 < t1 {
+        Some((t0, t1))
+    } else {
+        None
+    }
+}
+
+/// Order the polylines to keep the travel between them short, starting from the home
+/// position. Polylines can be flipped around to start from either end.
+pub fn order_polylines(polylines: Vec<Polyline>, home: Vector2<f64>) -> Vec<Polyline> {
+    let mut ordered = nearest_neighbor_order(polylines, home);
+    two_opt(&mut ordered, home);
+    ordered
+}
+
+/// One end of a polyline, for looking up the nearest polyline in an RTree.
+#[derive(Clone, Debug, PartialEq)]
+struct PolylineEnd {
+    point: Point2<f64>,
+    polyline_index: usize,
+    is_end: bool,
+}
+
+impl PolylineEnd {
+    fn new(point: Vector2<f64>, polyline_index: usize, is_end: bool) -> PolylineEnd {
+        PolylineEnd {
+            point: Point2::new(point.x, point.y),
+            polyline_index,
+            is_end,
+        }
+    }
+}
+
+impl SpatialObject for PolylineEnd {
+    type Point = Point2<f64>;
+
+    fn mbr(&self) -> BoundingRect<Point2<f64>> {
+        BoundingRect::from_point(self.point)
+    }
+
+    fn distance2(&self, point: &Point2<f64>) -> f64 {
+        self.point.distance2(*point)
+    }
+}
+
+/// Keep picking the polyline that starts or ends closest to the current pen position. Both
+/// ends of every polyline are kept in an RTree, so that each pick doesn't have to look
+/// through all of the remaining polylines.
+fn nearest_neighbor_order(polylines: Vec<Polyline>, home: Vector2<f64>) -> Vec<Polyline> {
+    let mut ordered = Vec::with_capacity(polylines.len());
+    let mut ends = RTree::bulk_load(
+        polylines
+            .iter()
+            .enumerate()
+            .flat_map(|(index, polyline)| {
+                vec![
+                    PolylineEnd::new(polyline.start(), index, false),
+                    PolylineEnd::new(polyline.end(), index, true),
+                ]
+            })
+            .collect(),
+    );
+    let mut remaining: Vec<Option<Polyline>> = polylines.into_iter().map(Some).collect();
+    let mut position = home;
+    while let Some(nearest) = ends
+        .nearest_neighbor(&Point2::new(position.x, position.y))
+        .cloned()
+    {
+        let mut polyline = remaining[nearest.polyline_index]
+            .take()
+            .expect("Both ends of a polyline are removed together");
+        let (start, end) = (polyline.start(), polyline.end());
+        ends.remove(&PolylineEnd::new(start, nearest.polyline_index, false));
+        ends.remove(&PolylineEnd::new(end, nearest.polyline_index, true));
+        if nearest.is_end {
+            polyline.reverse();
+        }
+        position = polyline.end();
+        ordered.push(polyline);
+    }
+    ordered
+}
+
+/// Improve the order by reversing runs of polylines, whenever that shortens the travel at
+/// either end of the run. Reversing a run also flips every polyline in it. Only the runs up
+/// to TWO_OPT_WINDOW polylines long are tried.
+fn two_opt(polylines: &mut [Polyline], home: Vector2<f64>) {
+    let count = polylines.len();
+    for _ in 0..MAX_TWO_OPT_PASSES {
+        let mut is_improved = false;
+        for i in 0..count {
+            let before = if i == 0 { home } else { polylines[i - 1].end() };
+            for j in (i + 1)..count.min(i + TWO_OPT_WINDOW) {
+                let first = polylines[i].start();
+                let last = polylines[j].end();
+                let mut current = (first - before).magnitude();
+                let mut reversed = (last - before).magnitude();
+                if j + 1 < count {
+                    let after = polylines[j + 1].start();
+                    current += (after - last).magnitude();
+                    reversed += (after - first).magnitude();
+                }
+                if reversed < current - 1e-9 {
+                    polylines[i..j + 1].reverse();
+                    for polyline in &mut polylines[i..j + 1] {
+                        polyline.reverse();
+                    }
+                    is_improved = true;
+                }
+            }
+        }
+        if !is_improved {
+            break;
+        }
+    }
+}
+
+/// The total distance traveled with the pen up, starting from the home position.
+pub fn travel_distance(polylines: &[Polyline], home: Vector2<f64>) -> f64 {
+    let mut position = home;
+    let mut distance = 0.0;
+    for polyline in polylines {
+        distance += (polyline.start() - position).magnitude();
+        position = polyline.end();
+    }
+    distance
+}
+
+fn to_svg(polylines: &[Polyline], options: &PlotOptions) -> String {
+    let (width, height) = options.page_size;
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}mm\" height=\"{}mm\" \
+         viewBox=\"0 0 {} {}\">",
+        width, height, width, height
+    )
+    .unwrap();
+    writeln!(
+        svg,
+        "<g fill=\"none\" stroke=\"#000\" stroke-width=\"{}\" stroke-linecap=\"round\" \
+         stroke-linejoin=\"round\">",
+        options.stroke_width
+    )
+    .unwrap();
+    for polyline in polylines {
+        svg.push_str("<path d=\"");
+        for (i, point) in polyline.points.iter().enumerate() {
+            let command = if i == 0 { "M" } else { " L" };
+            write!(svg, "{}{:.2},{:.2}", command, point.x, point.y).unwrap();
+        }
+        svg.push_str("\"/>\n");
+    }
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+fn to_hpgl(polylines: &[Polyline], options: &PlotOptions) -> String {
+    // HPGL has the origin in the bottom left, with the y axis going up.
+    let height = options.page_size.1;
+    let to_units = |point: &Vector2<f64>| {
+        (
+            (point.x * HPGL_UNITS_PER_MM).round() as i64,
+            ((height - point.y) * HPGL_UNITS_PER_MM).round() as i64,
+        )
+    };
+    let mut hpgl = String::from("IN;SP1;\n");
+    for polyline in polylines {
+        let (x, y) = to_units(&polyline.points[0]);
+        write!(hpgl, "PU{},{};PD", x, y).unwrap();
+        for (i, point) in polyline.points[1..].iter().enumerate() {
+            let (x, y) = to_units(point);
+            let separator = if i == 0 { "" } else { "," };
+            write!(hpgl, "{}{},{}", separator, x, y).unwrap();
+        }
+        hpgl.push_str(";\n");
+    }
+    hpgl.push_str("PU;SP0;\n");
+    hpgl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(from: (f64, f64), to: (f64, f64)) -> Polyline {
+        Polyline {
+            points: vec![Vector2::new(from.0, from.1), Vector2::new(to.0, to.1)],
+            nodes: vec![0],
+        }
+    }
+
+    #[test]
+    fn clip_line_to_the_rectangle() {
+        let min = Vector2::new(0.0, 0.0);
+        let max = Vector2::new(10.0, 10.0);
+        let inside = clip_line(Vector2::new(1.0, 1.0), Vector2::new(9.0, 9.0), min, max);
+        assert_eq!(inside, Some((0.0, 1.0)));
+        let crossing = clip_line(Vector2::new(-10.0, 5.0), Vector2::new(20.0, 5.0), min, max);
+        let (t0, t1) = crossing.unwrap();
+        assert!((t0 - 1.0 / 3.0).abs() < 1e-9);
+        assert!((t1 - 2.0 / 3.0).abs() < 1e-9);
+        let outside = clip_line(Vector2::new(-5.0, -5.0), Vector2::new(-1.0, 20.0), min, max);
+        assert_eq!(outside, None);
+    }
+
+    #[test]
+    fn order_keeps_every_polyline_and_shortens_the_travel() {
+        let home = Vector2::new(0.0, 0.0);
+        let polylines: Vec<Polyline> = (0..200)
+            .map(|index| {
+                // Spread the lines out in a scrambled order, with every other one backwards.
+                let x = ((index * 37) % 200) as f64;
+                if index % 2 == 0 {
+                    line((x, 0.0), (x + 0.5, 1.0))
+                } else {
+                    line((x + 0.5, 1.0), (x, 0.0))
+                }
+            })
+            .collect();
+        let before = travel_distance(&polylines, home);
+        let ordered = order_polylines(polylines, home);
+        assert_eq!(ordered.len(), 200);
+        let mut starts: Vec<i64> = ordered
+            .iter()
+            .map(|polyline| polyline.start().x.min(polyline.end().x) as i64)
+            .collect();
+        starts.sort();
+        assert_eq!(starts, (0..200).collect::<Vec<i64>>());
+        assert!(travel_distance(&ordered, home) < before / 10.0);
+    }
+
+    #[test]
+    fn order_empty() {
+        assert!(order_polylines(Vec::new(), Vector2::new(0.0, 0.0)).is_empty());
+    }
+}
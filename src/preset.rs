@@ -0,0 +1,253 @@
+use draw;
+use draw::{RootConfig, State};
+use std::cell::RefCell;
+use style::{ColorBy, Fade, GradientStop, Style, WidthBy};
+use tree_canvas::from_js;
+use tree_node::GrowthParameters;
+use wasm_bindgen::prelude::*;
+
+/// This module provides named presets, which bundle together the growth parameters and the
+/// style for a particular look. There are a few built-in presets, and more can be registered
+/// from JS at runtime. The registered presets are shared by every visualization on the page,
+/// but a worker has its own set.
+
+/// A combination of growth parameters and style, which can come from JS.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Preset {
+    /// The growth parameters for every root.
+    pub growth: GrowthParameters,
+    pub style: Style,
+}
+
+/// The names of the built-in presets, see builtin_preset.
+pub const BUILTIN_PRESETS: &[&str] = &["dense coral", "sparse winter", "lightning"];
+
+thread_local! {
+    /// The presets registered at runtime, in the order they were registered. These take
+    /// precedence over the built-in presets with the same name.
+    static CUSTOM_PRESETS: RefCell<Vec<(String, Preset)>> = RefCell::new(Vec::new());
+}
+
+fn gradient(colors: &[&str]) -> Vec<GradientStop> {
+    colors
+        .iter()
+        .enumerate()
+        .map(|(index, color)| GradientStop {
+            offset: index as f64 / (colors.len() - 1).max(1) as f64,
+            color: color.to_string(),
+        })
+        .collect()
+}
+
+fn builtin_preset(name: &str) -> Option<Preset> {
+    match name {
+        // Short, tightly packed branches that split often and curl around, colored by
+        // depth from pale pink at the base to deep red at the tips.
+        "dense coral" => Some(Preset {
+            growth: GrowthParameters {
+                grow_speed: (1.0, 3.0),
+                limb_length: (0.006, 0.02),
+                split_theta_range: 1.6,
+                max_tree_depth: 60,
+                split_count: 3,
+            },
+            style: Style {
+                color_by: ColorBy::Depth,
+                gradient: gradient(&["#ffd6c9", "#ff7f6e", "#e0445a", "#8c1c3a"]),
+                range: Some(60.0),
+                width_by: WidthBy::SubtreeSize,
+                line_width: 1.0,
+                trunk_width: 5.0,
+                smooth: true,
+                background: String::from("#1a0f1f"),
+                ..Style::default()
+            },
+        }),
+        // Long, straight branches that only split in two, and stop early, like bare trees
+        // in the snow.
+        "sparse winter" => Some(Preset {
+            growth: GrowthParameters {
+                grow_speed: (0.8, 2.0),
+                limb_length: (0.03, 0.07),
+                split_theta_range: 0.8,
+                max_tree_depth: 18,
+                split_count: 2,
+            },
+            style: Style {
+                color_by: ColorBy::Distance,
+                gradient: gradient(&["#ffffff", "#c9d8e6", "#8fa8c0"]),
+                width_by: WidthBy::Depth,
+                line_width: 0.75,
+                trunk_width: 3.0,
+                smooth: true,
+                background: String::from("#2b3440"),
+                ..Style::default()
+            },
+        }),
+        // Fast, jagged forks that leave a bright trail behind them.
+        "lightning" => Some(Preset {
+            growth: GrowthParameters {
+                grow_speed: (6.0, 12.0),
+                limb_length: (0.02, 0.06),
+                split_theta_range: 1.4,
+                max_tree_depth: 30,
+                split_count: 2,
+            },
+            style: Style {
+                color_by: ColorBy::Age,
+                gradient: gradient(&["#ffffff", "#b9a6ff", "#4b2dbf"]),
+                range: Some(120.0),
+                width_by: WidthBy::Depth,
+                line_width: 0.5,
+                trunk_width: 3.0,
+                background: String::from("#0b0820"),
+                fade: Fade {
+                    alpha: 6.0 / 255.0,
+                    strong_alpha: 10.0 / 255.0,
+                    duration: 1000,
+                    ..Fade::default()
+                },
+                ..Style::default()
+            },
+        }),
+        _ => None,
+    }
+}
+
+/// Look up a preset by name, from either the registered or the built-in presets.
+pub fn find(name: &str) -> Option<Preset> {
+    CUSTOM_PRESETS
+        .with(|presets| {
+            presets
+                .borrow()
+                .iter()
+                .find(|&&(ref preset_name, _)| preset_name == name)
+                .map(|&(_, ref preset)| preset.clone())
+        })
+        .or_else(|| builtin_preset(name))
+}
+
+/// Add a new preset, or replace the one with the same name. The growth parameters and the
+/// style are validated first.
+pub fn register(name: &str, preset: Preset) -> Result<(), String> {
+    preset.growth.validate()?;
+    preset.style.resolve(Vec::new(), 0, 1.0)?;
+    CUSTOM_PRESETS.with(|presets| {
+        let mut presets = presets.borrow_mut();
+        match presets
+            .iter_mut()
+            .find(|&&mut (ref preset_name, _)| preset_name == name)
+        {
+            Some(entry) => entry.1 = preset,
+            None => presets.push((name.to_string(), preset)),
+        }
+    });
+    Ok(())
+}
+
+/// The names of all of the presets, starting with the built-in ones.
+pub fn names() -> Vec<String> {
+    let mut names: Vec<String> = BUILTIN_PRESETS
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    CUSTOM_PRESETS.with(|presets| {
+        for &(ref name, _) in presets.borrow().iter() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    });
+    names
+}
+
+/// Switch to the style of a preset, and grow the roots again with its growth parameters.
+pub fn apply(state: &mut State, preset: &Preset, configs: &[RootConfig]) -> Result<(), String> {
+    let mut style = preset.style.clone();
+    // Transparency depends on how the canvas was mounted, so keep it as it is.
+    style.transparent = state.style.transparent;
+    draw::set_style(state, style)?;
+    state.growth = preset.growth;
+    let configs: Vec<RootConfig> = configs
+        .iter()
+        .map(|config| RootConfig {
+            growth: preset.growth,
+            ..config.clone()
+        })
+        .collect();
+    draw::set_roots(state, &configs);
+    Ok(())
+}
+
+/// Apply a preset by name to the current roots.
+pub fn load(state: &mut State, name: &str) -> Result<(), String> {
+    let preset = find(name).ok_or_else(|| format!("Unknown preset \"{}\"", name))?;
+    let configs: Vec<RootConfig> = state.roots.iter().map(|root| root.config.clone()).collect();
+    apply(state, &preset, &configs)
+}
+
+/// Register a preset from JS. The preset is either an object matching Preset, or a JSON
+/// string of one.
+#[wasm_bindgen]
+pub fn register_preset(name: &str, preset: JsValue) -> Result<(), JsValue> {
+    let preset = match preset.as_string() {
+        Some(json) => js_sys::JSON::parse(&json)?,
+        None => preset,
+    };
+    let preset: Preset = from_js(preset)?;
+    register(name, preset).map_err(|error| JsValue::from_str(&error))
+}
+
+/// Get the names of all of the presets, as an array of strings.
+#[wasm_bindgen]
+pub fn preset_names() -> JsValue {
+    JsValue::from_serde(&names()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_presets_are_valid() {
+        for name in BUILTIN_PRESETS {
+            let preset = builtin_preset(name).unwrap();
+            assert_eq!(preset.growth.validate(), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn register_rejects_runaway_growth() {
+        let invalid = [
+            GrowthParameters {
+                split_count: 0,
+                ..GrowthParameters::default()
+            },
+            GrowthParameters {
+                split_count: 1000,
+                ..GrowthParameters::default()
+            },
+            GrowthParameters {
+                max_tree_depth: 10_000,
+                ..GrowthParameters::default()
+            },
+            GrowthParameters {
+                grow_speed: (0.0, 1.0),
+                ..GrowthParameters::default()
+            },
+            GrowthParameters {
+                limb_length: (-0.01, 0.02),
+                ..GrowthParameters::default()
+            },
+        ];
+        for growth in &invalid {
+            let preset = Preset {
+                growth: *growth,
+                ..Preset::default()
+            };
+            assert!(register("runaway", preset).is_err(), "{:?}", growth);
+        }
+        assert!(!names().contains(&"runaway".to_string()));
+    }
+}
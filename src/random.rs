@@ -0,0 +1,69 @@
+/// This module provides the random numbers for growing the trees. Unlike Math.random, the
+/// generator can be seeded, so that the same trees can be grown again, for instance when
+/// exporting a video. It's a SplitMix64 generator, which is small and fast, and plenty good
+/// enough for picking angles and lengths. Every visualization has its own generator, so
+/// that several of them on the same page can't disturb each other's sequence.
+
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::new()
+    }
+}
+
+impl Random {
+    /// A generator seeded from Math.random, which can't be predicted.
+    pub fn new() -> Random {
+        Random::seeded(initial_seed())
+    }
+
+    /// A generator with a deterministic sequence of random numbers.
+    pub fn seeded(seed: u64) -> Random {
+        Random { state: seed }
+    }
+
+    /// Start a new deterministic sequence of random numbers.
+    pub fn seed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    /// Start a new sequence of random numbers that can't be predicted, e.g. to grow different
+    /// trees after a seeded export.
+    pub fn reseed(&mut self) {
+        self.seed(initial_seed());
+    }
+
+    /// Get a random number in the range [0, 1).
+    pub fn random(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        // Use the top 53 bits, which is all the precision a f64 has.
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Get a random number in the range [start, end).
+    pub fn range(&mut self, start: f64, end: f64) -> f64 {
+        self.random() * (end - start) + start
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn initial_seed() -> u64 {
+    (js_sys::Math::random() * (1u64 << 53) as f64) as u64
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn initial_seed() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() ^ duration.subsec_nanos() as u64)
+        .unwrap_or(0)
+}
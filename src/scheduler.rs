@@ -0,0 +1,389 @@
+use random::Random;
+use spade::rtree::RTree;
+use stats::Stats;
+use std::collections::VecDeque;
+use tree_node::{MutableNodes, TreeNodeIndex};
+use tree_node_ref::TreeNodeReference;
+use view::Boundary;
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+/// This module schedules the growth of the trees, so that the work done in a single frame
+/// stays within a budget. A growth step grows every tip of the trees by a fixed amount of
+/// time, which is cheap. The time between frames is accumulated, and then used up by running
+/// as many steps as it covers, so the trees grow at the same speed no matter the refresh rate
+/// of the display. The expensive part is splitting the tips that are fully grown, as every new node
+/// is checked for intersections and inserted into the R-tree. The splits are queued up and
+/// worked through until the time runs out, and are picked back up in the next frame.
+
+/// The amount of time that a single growth step covers, in seconds.
+pub const STEP_DURATION: f64 = 1.0 / 60.0;
+
+/// How much work can be done per frame. This can come from JS.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Budget {
+    /// The time in milliseconds that can be spent growing the trees every frame.
+    pub milliseconds: f64,
+    /// The most growth steps to run in a single frame, if there is time for them. More than
+    /// one step is needed to keep up on a slow display. This is multiplied by the speed when
+    /// the growth is sped up, so the time budget is what limits the fastest speed.
+    pub max_steps_per_frame: u32,
+}
+
+impl Default for Budget {
+    fn default() -> Budget {
+        Budget {
+            milliseconds: 8.0,
+            max_steps_per_frame: 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    pub budget: Budget,
+    /// Multiply the elapsed time by this amount, to speed up or slow down the growth.
+    pub speed: f64,
+    /// The timestamp of the last frame in milliseconds, from requestAnimationFrame.
+    last_timestamp: Option<f64>,
+    /// The time in seconds that hasn't been used up by growth steps yet.
+    accumulated: f64,
+    /// The nodes that have no children, and are still growing.
+    growing: Vec<TreeNodeIndex>,
+    /// The nodes that are fully grown, and are waiting to be split.
+    pending_splits: VecDeque<TreeNodeIndex>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler {
+            budget: Budget::default(),
+            speed: 1.0,
+            last_timestamp: None,
+            accumulated: 0.0,
+            growing: Vec::new(),
+            pending_splits: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler {
+    /// Start growing a new node, such as a root.
+    pub fn add_tip(&mut self, node_index: TreeNodeIndex) {
+        self.growing.push(node_index);
+    }
+
+    pub fn clear(&mut self) {
+        self.growing.clear();
+        self.pending_splits.clear();
+    }
+
+    /// Forget the time of the last frame, so that the next frame doesn't grow the trees.
+    pub fn reset_clock(&mut self) {
+        self.last_timestamp = None;
+        self.accumulated = 0.0;
+    }
+
+    /// Returns true when there is nothing left to grow.
+    pub fn is_idle(&self) -> bool {
+        self.growing.is_empty() && self.pending_splits.is_empty()
+    }
+
+    /// Grow the trees for a single frame, where the timestamp comes from
+    /// requestAnimationFrame. Steps are run until either the elapsed time is used up, the
+    /// budget runs out, or the maximum number of steps has been run. The tick is incremented
+    /// for every growth step. At least one split or step is always run when there is work to
+    /// do, so the trees keep growing even on a slow device. Returns how many growth steps
+    /// were run.
+    pub fn run(
+        &mut self,
+        nodes: &MutableNodes,
+        r_tree: &mut RTree<TreeNodeReference>,
+        boundary: &Boundary,
+        tick: &mut u32,
+        stats: &mut Stats,
+        rng: &mut Random,
+        timestamp: f64,
+    ) -> u32 {
+        let deadline = now() + self.budget.milliseconds;
+        let elapsed = match self.last_timestamp {
+            Some(last_timestamp) => (timestamp - last_timestamp).max(0.0) / 1000.0,
+            None => 0.0,
+        };
+        self.last_timestamp = Some(timestamp);
+        // Don't let the time pile up when the steps can't keep up, or after the page has been
+        // in the background, otherwise the trees would have to race to catch up.
+        let max_steps = (self.budget.max_steps_per_frame as f64 * self.speed.max(1.0)).ceil();
+        self.accumulated = (self.accumulated + elapsed * self.speed).min(STEP_DURATION * max_steps);
+        let max_steps = max_steps as u32;
+
+        let mut steps = 0;
+        let mut has_worked = false;
+        loop {
+            // Finish all of the splits from the previous step before starting another one,
+            // so that the trees grow the same way no matter how the work is spread out.
+            while !self.pending_splits.is_empty() {
+                if has_worked && now() >= deadline {
+                    return steps;
+                }
+                let node_index = self.pending_splits.pop_front().unwrap();
+                self.split(nodes, r_tree, stats, rng, node_index, *tick);
+                has_worked = true;
+            }
+
+            if self.growing.is_empty()
+                || self.accumulated < STEP_DURATION
+                || steps >= max_steps
+                || (has_worked && now() >= deadline)
+            {
+                return steps;
+            }
+            *tick += 1;
+            self.step(nodes, boundary, stats, *tick);
+            self.accumulated -= STEP_DURATION;
+            steps += 1;
+            has_worked = true;
+        }
+    }
+
+    /// Grow all of the tips, and queue up the ones that are fully grown to be split. The tick
+    /// is the one that this step grows the tips up to.
+    fn step(&mut self, nodes: &MutableNodes, boundary: &Boundary, stats: &mut Stats, tick: u32) {
+        let nodes_borrow = nodes.borrow();
+        let pending_splits = &mut self.pending_splits;
+        self.growing.retain(|&node_index| {
+            let mut node = nodes_borrow[node_index].borrow_mut();
+            if !node.grow(STEP_DURATION) {
+                return true;
+            }
+            node.completed_tick = Some(tick);
+            stats.node_completed(&node);
+            if node.can_split(boundary) {
+                pending_splits.push_back(node_index);
+            } else {
+                node.is_terminal = true;
+            }
+            false
+        });
+    }
+
+    fn split(
+        &mut self,
+        nodes: &MutableNodes,
+        r_tree: &mut RTree<TreeNodeReference>,
+        stats: &mut Stats,
+        rng: &mut Random,
+        node_index: TreeNodeIndex,
+        tick: u32,
+    ) {
+        // Don't hold onto the nodes while splitting, as the new children get pushed onto them.
+        let node_cell = nodes.borrow()[node_index].clone();
+        let mut node = node_cell.borrow_mut();
+        node.split_all(nodes, r_tree, rng, tick);
+        self.growing.extend(node.children.iter().cloned());
+        {
+            let nodes_borrow = nodes.borrow();
+            for &child_index in &node.children {
+                stats.add_node(&nodes_borrow[child_index].borrow());
+            }
+        }
+        stats.node_split(node.children.len());
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    /// The performance object is available in both the window and workers, so bind to the
+    /// global one.
+    #[wasm_bindgen(js_namespace = performance, js_name = now)]
+    fn performance_now() -> f64;
+}
+
+/// The current time in milliseconds.
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> f64 {
+    performance_now()
+}
+
+/// The current time in milliseconds, measured from the first time this is called.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> f64 {
+    use std::time::Instant;
+    thread_local! {
+        static START: Instant = Instant::now();
+    }
+    START.with(|start| {
+        let elapsed = start.elapsed();
+        elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1_000_000.0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tree_node::{GrowthParameters, TreeNode};
+
+    struct Trees {
+        scheduler: Scheduler,
+        nodes: MutableNodes,
+        r_tree: RTree<TreeNodeReference>,
+        tick: u32,
+        stats: Stats,
+        rng: Random,
+    }
+
+    /// Seed a single small tree, in the same way as draw::add_root.
+    fn seed_tree(budget: Budget) -> Trees {
+        let mut rng = Random::seeded(7);
+        let parameters = GrowthParameters {
+            max_tree_depth: 4,
+            split_count: 2,
+            ..GrowthParameters::default()
+        };
+        let mut node = TreeNode::with_parameters(0.0, 0.0, 0.0, 0.0, 0, parameters, &mut rng);
+        node.end.y += node.limb_length;
+        let nodes = RefCell::new(Vec::new());
+        let mut r_tree = RTree::new();
+        let mut stats = Stats::default();
+        node.insert_and_clip(0, &nodes, &mut r_tree);
+        stats.add_node(&node);
+        nodes.borrow_mut().push(Rc::new(RefCell::new(node)));
+        let mut scheduler = Scheduler {
+            budget,
+            ..Scheduler::default()
+        };
+        scheduler.add_tip(0);
+        Trees {
+            scheduler,
+            nodes,
+            r_tree,
+            tick: 0,
+            stats,
+            rng,
+        }
+    }
+
+    impl Trees {
+        fn run(&mut self, timestamp: f64) -> u32 {
+            self.scheduler.run(
+                &self.nodes,
+                &mut self.r_tree,
+                &Boundary::default(),
+                &mut self.tick,
+                &mut self.stats,
+                &mut self.rng,
+                timestamp,
+            )
+        }
+
+        /// Run frames at the frame interval until there is nothing left to grow.
+        fn grow_to_the_end(&mut self, frame_interval: f64) {
+            let mut timestamp = 0.0;
+            for _ in 0..10_000 {
+                if self.scheduler.is_idle() {
+                    return;
+                }
+                self.run(timestamp);
+                timestamp += frame_interval;
+            }
+            panic!("The trees never finished growing.");
+        }
+    }
+
+    /// A budget that never runs out of time, so that the tests don't depend on the speed of
+    /// the machine.
+    fn unlimited_time(max_steps_per_frame: u32) -> Budget {
+        Budget {
+            milliseconds: 1_000_000.0,
+            max_steps_per_frame,
+        }
+    }
+
+    #[test]
+    fn run_is_limited_by_the_elapsed_time_and_the_max_steps() {
+        let mut trees = seed_tree(unlimited_time(4));
+        // There is no elapsed time for the first frame.
+        assert_eq!(trees.run(1000.0), 0);
+        assert_eq!(trees.tick, 0);
+        // Two steps worth of time, with a little extra for the rounding.
+        assert_eq!(trees.run(1000.0 + 2.5 * STEP_DURATION * 1000.0), 2);
+        assert_eq!(trees.tick, 2);
+        // A long pause only runs the max steps, and doesn't pile up the time.
+        assert_eq!(trees.run(10_000.0), 4);
+        assert_eq!(trees.run(10_000.0), 0);
+        assert_eq!(trees.tick, 6);
+    }
+
+    #[test]
+    fn reset_clock_skips_the_time_in_between() {
+        let mut trees = seed_tree(unlimited_time(4));
+        trees.run(0.0);
+        trees.scheduler.reset_clock();
+        assert_eq!(trees.run(1000.0), 0);
+        assert_eq!(trees.tick, 0);
+    }
+
+    #[test]
+    fn speed_scales_the_steps() {
+        let mut trees = seed_tree(unlimited_time(4));
+        trees.scheduler.speed = 2.0;
+        trees.run(0.0);
+        assert_eq!(trees.run(2.5 * STEP_DURATION * 1000.0), 5);
+    }
+
+    #[test]
+    fn grows_the_same_trees_at_any_frame_rate() {
+        let mut fast = seed_tree(unlimited_time(4));
+        fast.grow_to_the_end(1000.0 / 120.0);
+        let mut slow = seed_tree(unlimited_time(4));
+        slow.grow_to_the_end(1000.0 / 20.0);
+
+        assert_eq!(fast.tick, slow.tick);
+        let fast_nodes = fast.nodes.borrow();
+        let slow_nodes = slow.nodes.borrow();
+        assert!(fast_nodes.len() > 1);
+        assert_eq!(fast_nodes.len(), slow_nodes.len());
+        for (fast_node, slow_node) in fast_nodes.iter().zip(slow_nodes.iter()) {
+            let (fast_node, slow_node) = (fast_node.borrow(), slow_node.borrow());
+            assert_eq!(fast_node.end, slow_node.end);
+            assert_eq!(fast_node.created_tick, slow_node.created_tick);
+            assert_eq!(fast_node.completed_tick, slow_node.completed_tick);
+        }
+    }
+
+    #[test]
+    fn the_recorded_ticks_replay_the_growth() {
+        let mut trees = seed_tree(unlimited_time(4));
+        trees.grow_to_the_end(1000.0 / 60.0);
+        for node in trees.nodes.borrow().iter() {
+            let node = node.borrow();
+            let completed_tick = node.completed_tick.unwrap();
+            assert_eq!(node.growth_length_at(node.created_tick as f64), 0.0);
+            assert_eq!(node.growth_length_at(completed_tick as f64), 1.0);
+            let halfway = (node.created_tick + completed_tick) as f64 / 2.0;
+            let growth_length = node.growth_length_at(halfway);
+            assert!(growth_length > 0.0 && growth_length < 1.0);
+        }
+    }
+
+    #[test]
+    fn stats_follow_the_growth() {
+        let mut trees = seed_tree(unlimited_time(4));
+        trees.grow_to_the_end(1000.0 / 60.0);
+        let nodes = trees.nodes.borrow();
+        let summary = trees.stats.summary(trees.tick, trees.r_tree.size());
+        assert_eq!(summary.node_count as usize, nodes.len());
+        let leaf_count = nodes
+            .iter()
+            .filter(|node| node.borrow().children.is_empty())
+            .count();
+        assert_eq!(summary.leaf_count as usize, leaf_count);
+        assert!(summary.max_depth <= 4);
+        assert_eq!(summary.r_tree_size, nodes.len());
+    }
+}
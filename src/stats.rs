@@ -0,0 +1,203 @@
+use cgmath::prelude::*;
+use tree_node::TreeNode;
+
+/// This module keeps statistics on how the trees grew. Walking every node to compute them
+/// would get slow as the trees get big, so they are updated incrementally by the scheduler,
+/// as nodes are created, finish growing, and split.
+
+/// How many cells across the coverage grid is. The grid spans the unit square, which is
+/// centered around (0, 0) in the unit interval space.
+const COVERAGE_GRID_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub struct Stats {
+    node_count: u32,
+    leaf_count: u32,
+    max_depth: i32,
+    depth_sum: u64,
+    /// How many nodes there are at each depth.
+    depth_histogram: Vec<u32>,
+    total_length: f64,
+    collision_count: u32,
+    /// Which cells of the coverage grid a fully grown branch has passed through.
+    covered_cells: Vec<bool>,
+    covered_count: u32,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            node_count: 0,
+            leaf_count: 0,
+            max_depth: 0,
+            depth_sum: 0,
+            depth_histogram: Vec::new(),
+            total_length: 0.0,
+            collision_count: 0,
+            covered_cells: vec![false; COVERAGE_GRID_SIZE * COVERAGE_GRID_SIZE],
+            covered_count: 0,
+        }
+    }
+}
+
+/// A snapshot of the statistics, that can be handed to JS.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Summary {
+    pub tick_count: u32,
+    pub node_count: u32,
+    /// The nodes that haven't split, including the tips that are still growing.
+    pub leaf_count: u32,
+    pub max_depth: i32,
+    pub mean_depth: f64,
+    /// The number of nodes at each depth, starting from the roots at depth 0.
+    pub depth_histogram: Vec<u32>,
+    /// The length of all of the fully grown branches, in the unit interval space.
+    pub total_length: f64,
+    /// How many branches were cut short by running into another branch.
+    pub collision_count: u32,
+    /// The fraction of the unit square that the fully grown branches pass through, measured
+    /// on a 64 by 64 grid.
+    pub coverage: f64,
+    /// How many lines are in the R-tree.
+    pub r_tree_size: usize,
+}
+
+impl Stats {
+    pub fn clear(&mut self) {
+        *self = Stats::default();
+    }
+
+    /// Count a newly created node.
+    pub fn add_node(&mut self, node: &TreeNode) {
+        let depth = node.depth.max(0);
+        self.node_count += 1;
+        self.leaf_count += 1;
+        self.max_depth = self.max_depth.max(depth);
+        self.depth_sum += depth as u64;
+        if self.depth_histogram.len() <= depth as usize {
+            self.depth_histogram.resize(depth as usize + 1, 0);
+        }
+        self.depth_histogram[depth as usize] += 1;
+        if node.is_collided {
+            self.collision_count += 1;
+        }
+    }
+
+    /// Count a node that finished growing.
+    pub fn node_completed(&mut self, node: &TreeNode) {
+        let line = node.end - node.start;
+        let length = line.magnitude();
+        self.total_length += length;
+
+        // Mark every cell along the line, stepping by half of a cell.
+        let cell_size = 1.0 / COVERAGE_GRID_SIZE as f64;
+        let samples = (length / (cell_size * 0.5)).ceil().max(1.0) as usize;
+        for sample in 0..samples + 1 {
+            let point = node.start + line * (sample as f64 / samples as f64);
+            let x = ((point.x + 0.5) / cell_size).floor();
+            let y = ((point.y + 0.5) / cell_size).floor();
+            let size = COVERAGE_GRID_SIZE as f64;
+            if x < 0.0 || y < 0.0 || x >= size || y >= size {
+                continue;
+            }
+            let cell = y as usize * COVERAGE_GRID_SIZE + x as usize;
+            if !self.covered_cells[cell] {
+                self.covered_cells[cell] = true;
+                self.covered_count += 1;
+            }
+        }
+    }
+
+    /// Count a node that split into some number of children, which are no longer leaves.
+    pub fn node_split(&mut self, child_count: usize) {
+        if child_count > 0 {
+            self.leaf_count -= 1;
+        }
+    }
+
+    pub fn summary(&self, tick_count: u32, r_tree_size: usize) -> Summary {
+        Summary {
+            tick_count,
+            node_count: self.node_count,
+            leaf_count: self.leaf_count,
+            max_depth: self.max_depth,
+            mean_depth: if self.node_count == 0 {
+                0.0
+            } else {
+                self.depth_sum as f64 / self.node_count as f64
+            },
+            depth_histogram: self.depth_histogram.clone(),
+            total_length: self.total_length,
+            collision_count: self.collision_count,
+            coverage: self.covered_count as f64 / self.covered_cells.len() as f64,
+            r_tree_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+
+    fn node(start: (f64, f64), end: (f64, f64), depth: i32) -> TreeNode {
+        TreeNode::new(
+            start.0,
+            start.1,
+            end.0,
+            end.1,
+            depth,
+            &mut Random::seeded(1),
+        )
+    }
+
+    #[test]
+    fn summary_counts_the_nodes() {
+        let mut stats = Stats::default();
+        stats.add_node(&node((0.0, 0.0), (0.0, 0.1), 0));
+        let mut collided = node((0.0, 0.1), (0.1, 0.2), 1);
+        collided.is_collided = true;
+        stats.add_node(&collided);
+        stats.add_node(&node((0.0, 0.1), (-0.1, 0.2), 1));
+        stats.node_split(2);
+
+        let summary = stats.summary(10, 3);
+        assert_eq!(summary.tick_count, 10);
+        assert_eq!(summary.node_count, 3);
+        assert_eq!(summary.leaf_count, 2);
+        assert_eq!(summary.max_depth, 1);
+        assert!((summary.mean_depth - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(summary.depth_histogram, vec![1, 2]);
+        assert_eq!(summary.collision_count, 1);
+        assert_eq!(summary.r_tree_size, 3);
+    }
+
+    #[test]
+    fn coverage_marks_the_cells_along_a_line() {
+        let mut stats = Stats::default();
+        // Cross the whole grid once, and then again over the same cells.
+        stats.node_completed(&node((-0.49, 0.001), (0.49, 0.001), 0));
+        stats.node_completed(&node((0.49, 0.001), (-0.49, 0.001), 0));
+        // Outside of the unit square.
+        stats.node_completed(&node((0.6, 0.6), (0.8, 0.8), 0));
+
+        let summary = stats.summary(0, 0);
+        let size = COVERAGE_GRID_SIZE as f64;
+        assert!((summary.coverage - size / (size * size)).abs() < 1e-9);
+        let total_length = 0.98 * 2.0 + 0.2 * 2.0f64.sqrt();
+        assert!((summary.total_length - total_length).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clear_resets_everything() {
+        let mut stats = Stats::default();
+        stats.add_node(&node((0.0, 0.0), (0.0, 0.1), 3));
+        stats.node_completed(&node((0.0, 0.0), (0.0, 0.1), 3));
+        stats.clear();
+        let summary = stats.summary(0, 0);
+        assert_eq!(summary.node_count, 0);
+        assert_eq!(summary.max_depth, 0);
+        assert_eq!(summary.mean_depth, 0.0);
+        assert_eq!(summary.coverage, 0.0);
+    }
+}
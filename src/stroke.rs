@@ -0,0 +1,152 @@
+use cgmath::Vector2;
+use std::collections::HashMap;
+use view::ViewTransform;
+use wasm_bindgen::prelude::*;
+
+/// A single drawing command. These are created in the unit interval space of the nodes, and
+/// then mapped into canvas device pixel space before being drawn.
+#[derive(Clone, Copy, Debug)]
+pub enum PathCommand {
+    MoveTo(Vector2<f64>),
+    LineTo(Vector2<f64>),
+    /// The control point, and then the end point.
+    QuadraticCurveTo(Vector2<f64>, Vector2<f64>),
+}
+
+impl PathCommand {
+    /// Apply a function to every point of the command, e.g. to change its coordinate space.
+    pub fn map<F>(self, f: F) -> PathCommand
+    where
+        F: Fn(Vector2<f64>) -> Vector2<f64>,
+    {
+        match self {
+            PathCommand::MoveTo(point) => PathCommand::MoveTo(f(point)),
+            PathCommand::LineTo(point) => PathCommand::LineTo(f(point)),
+            PathCommand::QuadraticCurveTo(control, point) => {
+                PathCommand::QuadraticCurveTo(f(control), f(point))
+            }
+        }
+    }
+}
+
+/// The canvas can only stroke a path with a single style, and each call to stroke has a cost.
+/// The StrokeBatches collect the path commands for all of the lines, grouped by their stroke
+/// style, so that they can be drawn with as few calls to stroke as possible.
+#[derive(Debug, Default)]
+pub struct StrokeBatches<'a> {
+    /// Remember the order the styles were first used in, so that drawing is stable.
+    order: Vec<StrokeStyle<'a>>,
+    commands: HashMap<StrokeStyle<'a>, Vec<PathCommand>>,
+}
+
+/// The color and the line width of a stroke. The width is in quarter device pixels, see
+/// ResolvedStyle::quantized_line_width.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StrokeStyle<'a> {
+    pub color: &'a str,
+    pub width: u32,
+}
+
+impl<'a> StrokeBatches<'a> {
+    pub fn new() -> StrokeBatches<'a> {
+        StrokeBatches::default()
+    }
+
+    pub fn add_path<I>(&mut self, style: StrokeStyle<'a>, path: I)
+    where
+        I: IntoIterator<Item = PathCommand>,
+    {
+        let order = &mut self.order;
+        self.commands
+            .entry(style)
+            .or_insert_with(|| {
+                order.push(style);
+                Vec::new()
+            })
+            .extend(path);
+    }
+
+    /// Get each batch, in the order the styles were first used.
+    pub fn batches(&self) -> Vec<(StrokeStyle<'a>, &[PathCommand])> {
+        self.order
+            .iter()
+            .map(|style| (*style, self.commands[style].as_slice()))
+            .collect()
+    }
+
+    /// Stroke each batch as a single path.
+    pub fn stroke(&self, ctx: &web_sys::CanvasRenderingContext2d) {
+        for style in &self.order {
+            ctx.begin_path();
+            ctx.set_stroke_style(&JsValue::from_str(style.color));
+            ctx.set_line_width(style.width as f64 / 4.0);
+            for command in &self.commands[style] {
+                match *command {
+                    PathCommand::MoveTo(point) => ctx.move_to(point.x, point.y),
+                    PathCommand::LineTo(point) => ctx.line_to(point.x, point.y),
+                    PathCommand::QuadraticCurveTo(control, point) => {
+                        ctx.quadratic_curve_to(control.x, control.y, point.x, point.y)
+                    }
+                }
+            }
+            ctx.stroke();
+        }
+    }
+}
+
+/// Once a line is fully drawn and won't change anymore, its path is kept around in the
+/// PathCache. The paths are in the unit interval space, grouped by their stroke style, so a
+/// full redraw after a resize or a camera change can replay them with a new transform,
+/// rather than walking through every node again.
+#[derive(Debug, Default)]
+pub struct PathCache {
+    /// The color and the quantized width, in the order they were first used.
+    order: Vec<(String, u32)>,
+    paths: HashMap<(String, u32), web_sys::Path2d>,
+}
+
+impl PathCache {
+    pub fn add_path<I>(&mut self, style: StrokeStyle, path: I)
+    where
+        I: IntoIterator<Item = PathCommand>,
+    {
+        let key = (style.color.to_string(), style.width);
+        let order = &mut self.order;
+        let path2d = self.paths.entry(key.clone()).or_insert_with(|| {
+            order.push(key);
+            web_sys::Path2d::new().expect("Unable to create a Path2D")
+        });
+        for command in path {
+            match command {
+                PathCommand::MoveTo(point) => path2d.move_to(point.x, point.y),
+                PathCommand::LineTo(point) => path2d.line_to(point.x, point.y),
+                PathCommand::QuadraticCurveTo(control, point) => {
+                    path2d.quadratic_curve_to(control.x, control.y, point.x, point.y)
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.order.clear();
+        self.paths.clear();
+    }
+
+    /// Stroke all of the cached paths, mapped through the view into canvas device pixel
+    /// space.
+    pub fn stroke(&self, ctx: &web_sys::CanvasRenderingContext2d, view: &ViewTransform) {
+        if self.order.is_empty() {
+            return;
+        }
+        let [a, b, c, d, e, f] = view.matrix();
+        ctx.set_transform(a, b, c, d, e, f).unwrap();
+        for key in &self.order {
+            let (ref color, width) = *key;
+            ctx.set_stroke_style(&JsValue::from_str(color));
+            // The line width is scaled by the transform as well.
+            ctx.set_line_width(width as f64 / 4.0 / view.scale);
+            ctx.stroke_with_path(&self.paths[key]);
+        }
+        ctx.reset_transform().unwrap();
+    }
+}
@@ -0,0 +1,446 @@
+use tree_node::TreeNode;
+
+/// The style controls how the visualization is drawn. Strokes can only have a single color
+/// per path, so rather than giving every line its own color, the gradient is split into a
+/// limited number of steps. The lines are then grouped by their color, so that there is only
+/// a single stroke call per step.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Style {
+    /// What value of the node the color is based on.
+    pub color_by: ColorBy,
+    /// The name of one of the built-in palettes, see PALETTES.
+    pub palette: String,
+    /// User supplied gradient stops, these take precedence over the palette when provided.
+    pub gradient: Vec<GradientStop>,
+    /// The value at which the end of the gradient is reached. When not provided, this depends
+    /// on the ColorBy value.
+    pub range: Option<f64>,
+    /// How many discrete colors the gradient is split into.
+    pub steps: usize,
+    /// What value of the node the line width is based on.
+    pub width_by: WidthBy,
+    /// The width of the thinnest lines at the tips of the tree, in CSS pixels.
+    pub line_width: f64,
+    /// The maximum width of the trunk of the tree, in CSS pixels.
+    pub trunk_width: f64,
+    /// Draw the chains of nodes as smooth curves, rather than straight lines. This only
+    /// affects the drawing, the intersections still use the straight lines.
+    pub smooth: bool,
+    /// The hex color that the canvas is cleared to.
+    pub background: String,
+    /// Clear the canvas to transparent instead of the background color, so that it can be
+    /// overlaid on top of other content. The fade is skipped, as it would build up an opaque
+    /// layer. This requires the context to be created with an alpha channel. When this is
+    /// left out, the canvas stays transparent if it was mounted as transparent.
+    pub transparent: Option<bool>,
+    /// The trailing glow that happens while the tree first grows.
+    pub fade: Fade,
+}
+
+/// While the tree first grows, a nearly transparent layer of color is drawn over the canvas
+/// every tick. The lines that were drawn in earlier ticks slowly fade out, which leaves a
+/// glowing trail behind the growing tips. Only the new parts of the lines are drawn every
+/// tick, so this is destructive, and the older lines can't be recovered until a full redraw.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Fade {
+    pub enabled: bool,
+    /// The hex color of the fade, which defaults to the background color.
+    pub color: Option<String>,
+    /// The opacity of the fade layer, from 0 to 1. There are only so many bits in the color
+    /// representation, so very small values have no effect.
+    pub alpha: f64,
+    /// A slightly stronger fade is applied some of the time, to fade out the lines further
+    /// than the regular alpha is able to.
+    pub strong_alpha: f64,
+    /// The fraction of ticks that get the strong fade.
+    pub strong_probability: f64,
+    /// How many ticks the fade lasts for.
+    pub duration: u32,
+}
+
+impl Default for Fade {
+    fn default() -> Fade {
+        Fade {
+            enabled: true,
+            color: None,
+            alpha: 2.0 / 255.0,
+            strong_alpha: 3.0 / 255.0,
+            strong_probability: 0.05,
+            duration: 400,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorBy {
+    /// Use the color that each root was configured with.
+    Root,
+    /// How many splits the node is away from its root.
+    Depth,
+    /// How many ticks ago the node was created. This is measured when the line is drawn, so
+    /// a full redraw recolors the lines as they age.
+    Age,
+    /// The tick at which the node was created.
+    CreationTick,
+    /// The length of the branches that lead back to the root.
+    Distance,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WidthBy {
+    /// All lines are drawn with the line_width.
+    Constant,
+    /// The lines taper from the trunk_width at the root, down to the line_width at the
+    /// maximum depth of the tree.
+    Depth,
+    /// The pipe model, where the area of a branch is the sum of the areas of the branches
+    /// it supports. The width grows with the square root of the number of nodes in the
+    /// subtree, up to the trunk_width.
+    SubtreeSize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GradientStop {
+    /// Where the stop is on the gradient, from 0 to 1.
+    pub offset: f64,
+    /// A hex color, e.g. "#fff" or "#ff8800".
+    pub color: String,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            color_by: ColorBy::Root,
+            palette: String::from("white"),
+            gradient: Vec::new(),
+            range: None,
+            steps: 16,
+            width_by: WidthBy::Constant,
+            line_width: 1.5,
+            trunk_width: 6.0,
+            smooth: false,
+            background: String::from("#333"),
+            transparent: None,
+            fade: Fade::default(),
+        }
+    }
+}
+
+/// The built-in palettes, as a list of hex colors that are evenly spaced along the gradient.
+pub const PALETTES: &[(&str, &[&str])] = &[
+    ("white", &["#fff", "#fff"]),
+    ("fire", &["#fff4c2", "#ffb000", "#e8400c", "#6b0f1a"]),
+    ("ocean", &["#e0fbfc", "#98c1d9", "#3d5a80", "#1b2a41"]),
+    ("forest", &["#6b4226", "#8a9a5b", "#b5e48c", "#e9f5db"]),
+    (
+        "sunset",
+        &["#ffcdb2", "#ffb4a2", "#e5989b", "#b5838d", "#6d6875"],
+    ),
+    (
+        "viridis",
+        &["#440154", "#3b528b", "#21918c", "#5ec962", "#fde725"],
+    ),
+];
+
+impl Style {
+    /// Resolve the colors of the style for the current tick, so that they don't need to be
+    /// re-computed for every single line. Fails if the palette or any of the colors are not
+    /// valid.
+    pub fn resolve(
+        &self,
+        root_colors: Vec<String>,
+        tick: u32,
+        device_pixel_ratio: f64,
+    ) -> Result<ResolvedStyle, String> {
+        let stops = self.gradient_stops()?;
+        let steps = self.steps.max(1);
+        let colors = (0..steps)
+            .map(|step| {
+                let t = if steps == 1 {
+                    0.0
+                } else {
+                    step as f64 / (steps - 1) as f64
+                };
+                to_hex(sample_gradient(&stops, t))
+            })
+            .collect();
+
+        let background = parse_color(&self.background)?;
+        let transparent = self.transparent.unwrap_or(false);
+        let fade = if self.fade.enabled && !transparent {
+            let color = match self.fade.color {
+                Some(ref color) => parse_color(color)?,
+                None => background,
+            };
+            Some(ResolvedFade {
+                color: to_hex_with_alpha(color, self.fade.alpha),
+                strong_color: to_hex_with_alpha(color, self.fade.strong_alpha),
+                strong_probability: self.fade.strong_probability.max(0.0).min(1.0),
+                duration: self.fade.duration,
+            })
+        } else {
+            None
+        };
+
+        Ok(ResolvedStyle {
+            background: to_hex(background),
+            transparent,
+            fade,
+            color_by: self.color_by,
+            range: self.range.unwrap_or(match self.color_by {
+                ColorBy::Root | ColorBy::Distance => 1.0,
+                ColorBy::Depth => 40.0,
+                ColorBy::Age => 200.0,
+                ColorBy::CreationTick => 1000.0,
+            }),
+            colors,
+            root_colors,
+            tick,
+            width_by: self.width_by,
+            line_width: self.line_width * device_pixel_ratio,
+            trunk_width: self.trunk_width.max(self.line_width) * device_pixel_ratio,
+            smooth: self.smooth,
+        })
+    }
+
+    /// Get the gradient stops as RGB values, either from the user supplied gradient or from
+    /// the named palette.
+    fn gradient_stops(&self) -> Result<Vec<(f64, [f64; 3])>, String> {
+        if !self.gradient.is_empty() {
+            let mut stops = self
+                .gradient
+                .iter()
+                .map(|stop| Ok((stop.offset, parse_color(&stop.color)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+            return Ok(stops);
+        }
+
+        let colors = PALETTES
+            .iter()
+            .find(|&&(name, _)| name == self.palette)
+            .map(|&(_, colors)| colors)
+            .ok_or_else(|| format!("Unknown palette \"{}\"", self.palette))?;
+
+        Ok(colors
+            .iter()
+            .enumerate()
+            .map(|(index, color)| {
+                (
+                    index as f64 / (colors.len() - 1).max(1) as f64,
+                    parse_hex(color).expect("The built-in palettes are valid"),
+                )
+            })
+            .collect())
+    }
+}
+
+/// The style, with all of the colors computed for the current tick.
+#[derive(Debug)]
+pub struct ResolvedStyle {
+    pub background: String,
+    pub transparent: bool,
+    pub fade: Option<ResolvedFade>,
+    pub color_by: ColorBy,
+    pub range: f64,
+    /// The gradient, split up into discrete steps.
+    pub colors: Vec<String>,
+    /// The color of each root, indexed by TreeNode::root_index.
+    pub root_colors: Vec<String>,
+    pub tick: u32,
+    pub width_by: WidthBy,
+    /// The line widths are in device pixels.
+    pub line_width: f64,
+    pub trunk_width: f64,
+    pub smooth: bool,
+}
+
+#[derive(Debug)]
+pub struct ResolvedFade {
+    /// The colors include the alpha, e.g. "#33333302".
+    pub color: String,
+    pub strong_color: String,
+    pub strong_probability: f64,
+    pub duration: u32,
+}
+
+impl ResolvedStyle {
+    /// Get the color of the fade layer to draw for a tick, if any. Rather than randomly
+    /// choosing when to apply the strong fade, it's spread out evenly over the ticks, so that
+    /// the results are the same every time.
+    pub fn fade_color(&self, tick: u32) -> Option<&str> {
+        let fade = self.fade.as_ref()?;
+        if tick >= fade.duration {
+            return None;
+        }
+        let tick = tick as f64;
+        let is_strong = ((tick + 1.0) * fade.strong_probability).floor()
+            > (tick * fade.strong_probability).floor();
+        Some(if is_strong {
+            &fade.strong_color
+        } else {
+            &fade.color
+        })
+    }
+
+    /// Look up the stroke color for a node.
+    pub fn color(&self, node: &TreeNode) -> &str {
+        let value = match self.color_by {
+            ColorBy::Root => {
+                return self
+                    .root_colors
+                    .get(node.root_index)
+                    .map(|color| color.as_str())
+                    .unwrap_or("#fff");
+            }
+            ColorBy::Depth => node.depth as f64,
+            ColorBy::Age => self.tick.saturating_sub(node.created_tick) as f64,
+            ColorBy::CreationTick => node.created_tick as f64,
+            ColorBy::Distance => node.distance_from_root,
+        };
+        let t = (value / self.range).max(0.0).min(1.0);
+        let step = (t * (self.colors.len() - 1) as f64).round() as usize;
+        &self.colors[step]
+    }
+
+    /// Whether the color of a node won't change anymore. Lines that are colored by their age
+    /// keep changing color until they reach the end of the range.
+    pub fn is_color_settled(&self, node: &TreeNode) -> bool {
+        match self.color_by {
+            ColorBy::Age => self.tick.saturating_sub(node.created_tick) as f64 >= self.range,
+            _ => true,
+        }
+    }
+
+    /// Compute the line width of a node in device pixels.
+    pub fn line_width(&self, node: &TreeNode) -> f64 {
+        match self.width_by {
+            WidthBy::Constant => self.line_width,
+            WidthBy::Depth => {
+                let t = (node.depth as f64 / node.max_tree_depth.max(1) as f64)
+                    .max(0.0)
+                    .min(1.0);
+                self.trunk_width + (self.line_width - self.trunk_width) * t
+            }
+            WidthBy::SubtreeSize => {
+                (self.line_width * (node.subtree_size as f64).sqrt()).min(self.trunk_width)
+            }
+        }
+    }
+
+    /// The line width is quantized into quarter device pixels, so that lines of a similar
+    /// width can share a single stroke.
+    pub fn quantized_line_width(&self, node: &TreeNode) -> u32 {
+        (self.line_width(node) * 4.0).round().max(1.0) as u32
+    }
+}
+
+/// Parse either a "#rgb" or a "#rrggbb" color into RGB values from 0 to 255.
+pub fn parse_hex(color: &str) -> Option<[f64; 3]> {
+    let hex = color.trim_start_matches('#');
+    let channel = |digits: &str| {
+        u8::from_str_radix(digits, 16)
+            .ok()
+            .map(|value| value as f64)
+    };
+    match hex.len() {
+        3 => {
+            let mut rgb = [0.0; 3];
+            for (index, digit) in hex.chars().enumerate() {
+                let digits: String = [digit, digit].iter().collect();
+                rgb[index] = channel(&digits)?;
+            }
+            Some(rgb)
+        }
+        6 => Some([
+            channel(hex.get(0..2)?)?,
+            channel(hex.get(2..4)?)?,
+            channel(hex.get(4..6)?)?,
+        ]),
+        _ => None,
+    }
+}
+
+fn parse_color(color: &str) -> Result<[f64; 3], String> {
+    parse_hex(color).ok_or_else(|| format!("Unable to parse the color \"{}\"", color))
+}
+
+fn to_hex_with_alpha(rgb: [f64; 3], alpha: f64) -> String {
+    format!(
+        "{}{:02x}",
+        to_hex(rgb),
+        (alpha.max(0.0).min(1.0) * 255.0).round() as u8
+    )
+}
+
+fn to_hex(rgb: [f64; 3]) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        rgb[0].round() as u8,
+        rgb[1].round() as u8,
+        rgb[2].round() as u8
+    )
+}
+
+/// Linearly interpolate between the sorted gradient stops.
+fn sample_gradient(stops: &[(f64, [f64; 3])], t: f64) -> [f64; 3] {
+    let first = stops
+        .first()
+        .expect("A gradient must have at least one stop.");
+    let last = stops
+        .last()
+        .expect("A gradient must have at least one stop.");
+    if t <= first.0 {
+        return first.1;
+    }
+    if t >= last.0 {
+        return last.1;
+    }
+    for pair in stops.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        if t <= b.0 {
+            let mix = if b.0 == a.0 {
+                0.0
+            } else {
+                (t - a.0) / (b.0 - a.0)
+            };
+            return [
+                a.1[0] + (b.1[0] - a.1[0]) * mix,
+                a.1[1] + (b.1[1] - a.1[1]) * mix,
+                a.1[2] + (b.1[2] - a.1[2]) * mix,
+            ];
+        }
+    }
+    last.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex_colors() {
+        assert_eq!(parse_hex("#ff8000"), Some([255.0, 128.0, 0.0]));
+        assert_eq!(parse_hex("#f80"), Some([255.0, 136.0, 0.0]));
+        assert_eq!(parse_hex("fff"), Some([255.0, 255.0, 255.0]));
+        assert_eq!(parse_hex("#ff80"), None);
+        assert_eq!(parse_hex("#ggg"), None);
+    }
+
+    #[test]
+    fn sample_gradient_between_stops() {
+        let stops = [
+            (0.0, [0.0, 0.0, 0.0]),
+            (0.5, [100.0, 0.0, 0.0]),
+            (1.0, [100.0, 200.0, 0.0]),
+        ];
+        assert_eq!(sample_gradient(&stops, -1.0), [0.0, 0.0, 0.0]);
+        assert_eq!(sample_gradient(&stops, 0.25), [50.0, 0.0, 0.0]);
+        assert_eq!(sample_gradient(&stops, 0.75), [100.0, 100.0, 0.0]);
+        assert_eq!(sample_gradient(&stops, 2.0), [100.0, 200.0, 0.0]);
+    }
+}
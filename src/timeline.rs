@@ -0,0 +1,166 @@
+use cgmath::Vector2;
+use draw;
+use draw::State;
+use scheduler::STEP_DURATION;
+use stroke::{PathCommand, StrokeBatches, StrokeStyle};
+use style::ResolvedStyle;
+use tree_node::TreeNodeIndex;
+use view::ViewTransform;
+
+/// This module plays back the recorded growth of the trees. Every node remembers the tick it
+/// was created at, and the tick it finished growing at, so the trees can be drawn as they
+/// were at any earlier tick without re-simulating them. While the timeline is being played
+/// back, the growth is paused. The fade is left out of the playback, as it depends on every
+/// frame that was drawn before.
+
+/// Where the playback is in the timeline.
+#[derive(Clone, Copy, Debug)]
+pub struct Playback {
+    /// The tick that is being shown. This is fractional while playing.
+    pub tick: f64,
+    /// How fast to play, where 1.0 is the speed the trees grew at, and a negative rate plays
+    /// backwards. A rate of 0.0 is paused.
+    pub rate: f64,
+    /// The timestamp of the last frame in milliseconds, from requestAnimationFrame.
+    last_timestamp: Option<f64>,
+    /// The tick that is currently on the canvas.
+    drawn_tick: Option<f64>,
+}
+
+impl Playback {
+    pub fn new(tick: f64, rate: f64) -> Playback {
+        Playback {
+            tick,
+            rate,
+            last_timestamp: None,
+            drawn_tick: None,
+        }
+    }
+}
+
+/// Show the trees as they were at a tick, and pause there.
+pub fn scrub(state: &mut State, tick: f64) {
+    let tick = tick.max(0.0).min(state.tick_count as f64);
+    state.playback = Some(Playback::new(tick, 0.0));
+}
+
+/// Play through the timeline, starting from the current position in the playback, or from
+/// the end if the trees are still growing.
+pub fn play(state: &mut State, rate: f64) {
+    let tick = match state.playback {
+        Some(playback) => playback.tick,
+        None => state.tick_count as f64,
+    };
+    state.playback = Some(Playback::new(tick, rate));
+}
+
+/// Leave the playback, and continue growing the trees.
+pub fn resume_growth(state: &mut State) {
+    state.playback = None;
+    state.force_redraw = true;
+}
+
+/// Advance the playback, and draw the trees if they changed.
+pub fn tick(state: &mut State, timestamp: f64) {
+    let end = state.tick_count as f64;
+    let mut playback = match state.playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    if let Some(last_timestamp) = playback.last_timestamp {
+        let elapsed = (timestamp - last_timestamp).max(0.0) / 1000.0;
+        playback.tick += playback.rate * elapsed / STEP_DURATION;
+    }
+    playback.last_timestamp = Some(timestamp);
+    if (playback.rate < 0.0 && playback.tick <= 0.0)
+        || (playback.rate > 0.0 && playback.tick >= end)
+    {
+        // Stop at either end of the timeline.
+        playback.rate = 0.0;
+    }
+    playback.tick = playback.tick.max(0.0).min(end);
+
+    if state.force_redraw || playback.drawn_tick != Some(playback.tick) {
+        draw_at_tick(state, playback.tick);
+        playback.drawn_tick = Some(playback.tick);
+        state.force_redraw = false;
+    }
+    state.playback = Some(playback);
+}
+
+/// Draw all of the nodes as they were at a tick.
+fn draw_at_tick(state: &State, tick: f64) {
+    let view = state.view();
+    let style = draw::resolve_style(state, tick as u32);
+    draw::clear_canvas(&state.page, &style);
+
+    let mut batches = StrokeBatches::new();
+    add_trees_at_tick(state, &mut batches, &style, &view, tick);
+    batches.stroke(&state.page.ctx);
+}
+
+/// Add the paths of all of the nodes as they were at a tick, mapped through the view.
+pub fn add_trees_at_tick<'a>(
+    state: &State,
+    batches: &mut StrokeBatches<'a>,
+    style: &'a ResolvedStyle,
+    view: &ViewTransform,
+    tick: f64,
+) {
+    for root in &state.roots {
+        add_node_at_tick(state, root.node_index, batches, style, view, tick, None);
+    }
+}
+
+fn add_node_at_tick<'a>(
+    state: &State,
+    node_index: TreeNodeIndex,
+    batches: &mut StrokeBatches<'a>,
+    style: &'a ResolvedStyle,
+    view: &ViewTransform,
+    tick: f64,
+    parent_start: Option<Vector2<f64>>,
+) {
+    let node_cell = state.nodes.borrow()[node_index].clone();
+    let node = node_cell.borrow();
+    if tick < node.created_tick as f64 {
+        return;
+    }
+    let growth_length = node.growth_length_at(tick);
+    let end = node.end_at(growth_length);
+    let children: Vec<TreeNodeIndex> = node
+        .children
+        .iter()
+        .cloned()
+        .filter(|&child_index| {
+            tick >= state.nodes.borrow()[child_index].borrow().created_tick as f64
+        })
+        .collect();
+
+    let path = if style.smooth {
+        let is_tip = growth_length == 1.0 && children.is_empty();
+        node.smooth_path(parent_start, 0.0, growth_length, is_tip)
+    } else {
+        vec![PathCommand::MoveTo(node.start), PathCommand::LineTo(end)]
+    };
+    batches.add_path(
+        StrokeStyle {
+            color: style.color(&node),
+            width: style.quantized_line_width(&node),
+        },
+        path.into_iter()
+            .map(|command| command.map(|point| view.unit_to_page(point))),
+    );
+
+    for child_index in children {
+        add_node_at_tick(
+            state,
+            child_index,
+            batches,
+            style,
+            view,
+            tick,
+            Some(node.start),
+        );
+    }
+}
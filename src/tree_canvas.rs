@@ -0,0 +1,394 @@
+use dom;
+use draw;
+use export;
+use gallery;
+use gcode;
+use input;
+use pdf;
+use plot;
+use preset;
+use std::cell::RefCell;
+use std::rc::Rc;
+use style;
+use timeline;
+use view;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::console;
+
+/// The TreeCanvas is the handle that JS uses to control a single visualization. Each one is
+/// mounted onto its own canvas element, and has its own state and requestAnimationFrame
+/// loop, so there can be multiple independent trees on a single page.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct TreeCanvas {
+    state: Rc<RefCell<draw::State>>,
+    /// The JS callback for when the trees are done growing.
+    on_complete: Rc<RefCell<Option<js_sys::Function>>>,
+    /// Everything that keeps the visualization running on the page, which is dropped when
+    /// the canvas is destroyed.
+    mounted: Rc<RefCell<Option<Mounted>>>,
+}
+
+/// The animation loop, listeners, and observers of a mounted TreeCanvas. Dropping these
+/// stops them.
+struct Mounted {
+    _animation_loop: dom::AnimationLoop,
+    _listeners: Vec<dom::EventListener>,
+    _resize_observer: Option<dom::ElementResizeObserver>,
+    _device_pixel_ratio_watcher: dom::DevicePixelRatioWatcher,
+}
+
+/// The CustomEvent that is dispatched on the canvas when the trees are done growing. The
+/// detail of the event is the final statistics.
+pub const COMPLETE_EVENT: &str = "growthcomplete";
+
+/// The options for mounting a TreeCanvas, which can come from JS.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MountOptions {
+    /// A fixed [width, height] in CSS pixels. By default the canvas is sized to match the
+    /// space it takes up on the page, and is resized along with it.
+    pub size: Option<(f64, f64)>,
+    /// Create the context with an alpha channel, and draw with a transparent background.
+    pub transparent: bool,
+    /// How the visualization is framed within the canvas. This is set before the trees
+    /// start growing, so that the growth region matches the canvas.
+    pub framing: view::Framing,
+}
+
+impl TreeCanvas {
+    /// Set up the canvas, and start running the visualization on it.
+    pub fn mount(canvas: web_sys::HtmlCanvasElement, options: &MountOptions) -> TreeCanvas {
+        // Track canvas resizing across callbacks.
+        let canvas_is_resized = Rc::new(RefCell::new(true));
+
+        let (resize_observer, device_pixel_ratio_watcher) = match options.size {
+            Some((width, height)) => {
+                // The CSS size is fixed, but the backing store still follows the device
+                // pixel ratio.
+                dom::set_canvas_css_size(&canvas, width, height);
+                let set_size = {
+                    let canvas = canvas.clone();
+                    let canvas_is_resized = canvas_is_resized.clone();
+                    move || {
+                        let device_pixel_ratio = dom::window().device_pixel_ratio();
+                        dom::set_canvas_size(
+                            &canvas,
+                            width * device_pixel_ratio,
+                            height * device_pixel_ratio,
+                        );
+                        *canvas_is_resized.borrow_mut() = true;
+                    }
+                };
+                set_size();
+                (None, dom::on_device_pixel_ratio_change(set_size))
+            }
+            None => {
+                // Set the canvas size and then observe the element to resize it along with
+                // the page. The size of the element doesn't always change along with the
+                // device pixel ratio, so watch that as well.
+                dom::pin_intrinsic_css_size(&canvas);
+                let set_size = {
+                    let canvas = canvas.clone();
+                    let canvas_is_resized = canvas_is_resized.clone();
+                    move || {
+                        dom::set_canvas_to_client_size(&canvas);
+                        *canvas_is_resized.borrow_mut() = true;
+                    }
+                };
+                set_size();
+                (
+                    Some(dom::on_element_resize(canvas.as_ref(), set_size.clone())),
+                    dom::on_device_pixel_ratio_change(set_size),
+                )
+            }
+        };
+
+        let state = Rc::new(RefCell::new(draw::init(dom::PageState {
+            width: canvas.width() as f64,
+            height: canvas.height() as f64,
+            device_pixel_ratio: dom::window().device_pixel_ratio(),
+            is_resized: true,
+            alpha: options.transparent,
+            ctx: dom::get_context(&canvas, options.transparent),
+        })));
+        state.borrow_mut().style.transparent = Some(options.transparent);
+        draw::set_framing(&mut state.borrow_mut(), options.framing);
+
+        let listeners = input::listen_to_canvas(&canvas, state.clone());
+        let on_complete = Rc::new(RefCell::new(None::<js_sys::Function>));
+
+        let animation_loop = dom::start_raf({
+            let state = state.clone();
+            let on_complete = on_complete.clone();
+            move |timestamp| {
+                let (export_frame, completion) = {
+                    let mut state = state.borrow_mut();
+                    let is_resized = canvas_is_resized.borrow().clone();
+                    if is_resized {
+                        state.page.width = canvas.width() as f64;
+                        state.page.height = canvas.height() as f64;
+                        state.page.device_pixel_ratio = dom::window().device_pixel_ratio();
+                        state.page.is_resized = true;
+                        *canvas_is_resized.borrow_mut() = false;
+                    }
+
+                    draw::tick(&mut state, timestamp);
+                    state.page.is_resized = false;
+                    (
+                        export::take_frame(&mut state),
+                        draw::take_completion(&mut state),
+                    )
+                };
+
+                if let Some(export_frame) = export_frame {
+                    if let Err(error) = export_frame.call() {
+                        console::error_1(&error);
+                    }
+                }
+
+                if let Some(stats) = completion {
+                    let detail = JsValue::from_serde(&stats).unwrap();
+                    // Don't hold onto the callback while calling it, as it's free to replace
+                    // itself.
+                    let callback = on_complete.borrow().clone();
+                    if let Some(callback) = callback {
+                        if let Err(error) = callback.call1(&JsValue::NULL, &detail) {
+                            console::error_1(&error);
+                        }
+                    }
+                    dom::dispatch_custom_event(canvas.as_ref(), COMPLETE_EVENT, &detail);
+                }
+            }
+        });
+
+        TreeCanvas {
+            state,
+            on_complete,
+            mounted: Rc::new(RefCell::new(Some(Mounted {
+                _animation_loop: animation_loop,
+                _listeners: listeners,
+                _resize_observer: resize_observer,
+                _device_pixel_ratio_watcher: device_pixel_ratio_watcher,
+            }))),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl TreeCanvas {
+    /// Mount a new visualization from JS. The canvas is either a canvas element, or a CSS
+    /// selector for one. The options are an object matching MountOptions, or undefined.
+    #[wasm_bindgen(constructor)]
+    pub fn new(canvas: JsValue, options: JsValue) -> Result<TreeCanvas, JsValue> {
+        let canvas = dom::canvas_from_js(canvas)?;
+        let options: MountOptions = from_js_or_default(options)?;
+        Ok(TreeCanvas::mount(canvas, &options))
+    }
+
+    /// Stop the visualization, and remove everything it added to the page. The canvas is
+    /// left with whatever was last drawn on it.
+    pub fn destroy(&self) {
+        // Take it out first, as the animation loop could be destroying itself from within
+        // a callback that also borrows it.
+        let mounted = self.mounted.borrow_mut().take();
+        drop(mounted);
+    }
+
+    /// Seed a new independent tree. The config is an object matching draw::RootConfig,
+    /// where any missing values are filled in with the defaults.
+    pub fn add_root(&self, config: JsValue) -> Result<(), JsValue> {
+        let config: draw::RootConfig = from_js(config)?;
+        draw::add_root(&mut self.state.borrow_mut(), &config);
+        Ok(())
+    }
+
+    /// Replace all of the trees with a new set of roots, from an array of root configs.
+    pub fn set_roots(&self, configs: JsValue) -> Result<(), JsValue> {
+        let configs: Vec<draw::RootConfig> = from_js(configs)?;
+        draw::set_roots(&mut self.state.borrow_mut(), &configs);
+        Ok(())
+    }
+
+    /// Change the camera, from an object matching view::Camera. Any missing values are
+    /// filled in with the defaults, so calling this with an empty object resets the camera.
+    pub fn set_camera(&self, camera: JsValue) -> Result<(), JsValue> {
+        let camera: view::Camera = from_js(camera)?;
+        draw::set_camera(&mut self.state.borrow_mut(), camera)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
+    /// Change how the visualization is framed within the canvas, from an object matching
+    /// view::Framing.
+    pub fn set_framing(&self, framing: JsValue) -> Result<(), JsValue> {
+        let framing: view::Framing = from_js(framing)?;
+        draw::set_framing(&mut self.state.borrow_mut(), framing);
+        Ok(())
+    }
+
+    /// Change how the lines are drawn, from an object matching style::Style. Any missing
+    /// values are filled in with the defaults.
+    pub fn set_style(&self, style: JsValue) -> Result<(), JsValue> {
+        let style: style::Style = from_js(style)?;
+        draw::set_style(&mut self.state.borrow_mut(), style)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
+    /// Change how much work is done per frame, from an object matching scheduler::Budget.
+    pub fn set_budget(&self, budget: JsValue) -> Result<(), JsValue> {
+        self.state.borrow_mut().scheduler.budget = from_js(budget)?;
+        Ok(())
+    }
+
+    /// Speed up or slow down the growth of the trees, where 1.0 is the normal speed.
+    pub fn set_speed(&self, speed: f64) {
+        self.state.borrow_mut().scheduler.speed = speed.max(0.0);
+    }
+
+    /// How many ticks of growth have been recorded in the timeline.
+    pub fn timeline_length(&self) -> u32 {
+        self.state.borrow().tick_count
+    }
+
+    /// Pause the growth, and show the trees as they were at a tick in the timeline.
+    pub fn scrub(&self, tick: f64) {
+        timeline::scrub(&mut self.state.borrow_mut(), tick);
+    }
+
+    /// Play through the timeline at a rate relative to the speed the trees grew at, where a
+    /// negative rate plays backwards.
+    pub fn play_timeline(&self, rate: f64) {
+        timeline::play(&mut self.state.borrow_mut(), rate);
+    }
+
+    /// Show the trees at the end of the timeline.
+    pub fn jump_to_end(&self) {
+        let mut state = self.state.borrow_mut();
+        let end = state.tick_count as f64;
+        timeline::scrub(&mut state, end);
+    }
+
+    /// Leave the timeline, and continue growing the trees.
+    pub fn resume_growth(&self) {
+        timeline::resume_growth(&mut self.state.borrow_mut());
+    }
+
+    /// Export the growth animation frame by frame. The options are an object matching
+    /// export::ExportOptions. The callback is called with the index of the frame and the total
+    /// number of frames, right after each frame is drawn on the canvas.
+    pub fn export_frames(
+        &self,
+        options: JsValue,
+        callback: js_sys::Function,
+    ) -> Result<(), JsValue> {
+        let options: export::ExportOptions = from_js_or_default(options)?;
+        export::start(&mut self.state.borrow_mut(), options, callback)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
+    /// Stop exporting frames, and go back to growing the trees in real time.
+    pub fn cancel_export(&self) {
+        export::cancel(&mut self.state.borrow_mut());
+    }
+
+    /// Create a file for a pen plotter, in either SVG or HPGL. The options are an object
+    /// matching plot::PlotOptions.
+    pub fn export_plot(&self, options: JsValue) -> Result<String, JsValue> {
+        let options: plot::PlotOptions = from_js_or_default(options)?;
+        Ok(plot::plot(&self.state.borrow(), &options))
+    }
+
+    /// Create a vector PDF for print, with the trees framed in the same way as on the canvas.
+    /// The options are an object matching pdf::PdfOptions.
+    pub fn export_pdf(&self, options: JsValue) -> Result<String, JsValue> {
+        let options: pdf::PdfOptions = from_js_or_default(options)?;
+        Ok(pdf::pdf(&self.state.borrow(), &options))
+    }
+
+    /// Create G-code for a CNC machine or a laser, with the trees placed as they are framed
+    /// on the canvas. The options are an object matching gcode::GcodeOptions.
+    pub fn export_gcode(&self, options: JsValue) -> Result<String, JsValue> {
+        let options: gcode::GcodeOptions = from_js_or_default(options)?;
+        Ok(gcode::gcode(&self.state.borrow(), &options))
+    }
+
+    /// Summarize the G-code toolpath without creating it, so that the bounds can be checked
+    /// before cutting. Returns an object matching gcode::GcodeSummary.
+    pub fn gcode_summary(&self, options: JsValue) -> Result<JsValue, JsValue> {
+        let options: gcode::GcodeOptions = from_js_or_default(options)?;
+        let summary = gcode::dry_run(&self.state.borrow(), &options);
+        Ok(JsValue::from_serde(&summary).unwrap())
+    }
+
+    /// Call the callback with the final statistics every time the trees are done growing,
+    /// or pass null to stop calling it. A "growthcomplete" CustomEvent is dispatched on the
+    /// canvas as well.
+    pub fn on_complete(&self, callback: JsValue) {
+        *self.on_complete.borrow_mut() = callback.dyn_into::<js_sys::Function>().ok();
+    }
+
+    /// Switch to one of the named presets, such as "dense coral", and grow the current
+    /// roots again with it. See preset_names for the names.
+    pub fn load_preset(&self, name: &str) -> Result<(), JsValue> {
+        preset::load(&mut self.state.borrow_mut(), name).map_err(|error| JsValue::from_str(&error))
+    }
+
+    /// Start an attract mode, where the trees are held once they are done growing, faded
+    /// out, and then grown again with a new seed. The options are an object matching
+    /// gallery::GalleryOptions, or undefined to use the defaults.
+    pub fn start_gallery(&self, options: JsValue) -> Result<(), JsValue> {
+        let options: gallery::GalleryOptions = from_js_or_default(options)?;
+        gallery::start(&mut self.state.borrow_mut(), options);
+        Ok(())
+    }
+
+    /// Stop the attract mode, and leave the current trees on the canvas.
+    pub fn stop_gallery(&self) {
+        gallery::stop(&mut self.state.borrow_mut());
+    }
+
+    /// Returns true once every tip has stopped growing.
+    pub fn is_complete(&self) -> bool {
+        self.state.borrow().is_complete
+    }
+
+    /// Get the statistics on how the trees grew, as an object matching stats::Summary.
+    pub fn stats(&self) -> JsValue {
+        JsValue::from_serde(&draw::stats(&self.state.borrow())).unwrap()
+    }
+
+    /// Look up the node under a point on the canvas. The point is in CSS pixels relative to
+    /// the canvas, such as the offsetX and offsetY of a mouse event. Returns null if there
+    /// is no node near the point.
+    pub fn node_at(&self, x: f64, y: f64) -> JsValue {
+        let state = self.state.borrow();
+        let ratio = state.page.device_pixel_ratio;
+        match draw::node_at(&state, x * ratio, y * ratio) {
+            Some(info) => JsValue::from_serde(&info).unwrap(),
+            None => JsValue::NULL,
+        }
+    }
+}
+
+/// Deserialize a value coming from JS, and turn any errors into JS errors.
+pub fn from_js<T>(value: JsValue) -> Result<T, JsValue>
+where
+    for<'a> T: serde::Deserialize<'a>,
+{
+    value
+        .into_serde()
+        .map_err(|error| JsValue::from_str(&error.to_string()))
+}
+
+/// Deserialize optional options coming from JS, where undefined or null uses the defaults.
+pub fn from_js_or_default<T>(value: JsValue) -> Result<T, JsValue>
+where
+    for<'a> T: serde::Deserialize<'a> + Default,
+{
+    if value.is_undefined() || value.is_null() {
+        Ok(T::default())
+    } else {
+        from_js(value)
+    }
+}
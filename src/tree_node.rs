@@ -1,17 +1,90 @@
 use cgmath::prelude::*;
 use cgmath::{Point2, Vector2};
-use dom;
+use random::Random;
+use scheduler::STEP_DURATION;
 use spade::rtree::RTree;
 use spade::BoundingRect;
 use std::cell::RefCell;
 use std::rc::Rc;
+use stroke::{PathCache, PathCommand, StrokeBatches, StrokeStyle};
+use style::{ResolvedStyle, WidthBy};
 use tree_node_ref::TreeNodeReference;
+use view::{Boundary, ViewTransform};
 
 #[allow(unused_imports)]
 use web_sys::console;
 
 pub type MutableNodes = RefCell<Vec<Rc<RefCell<TreeNode>>>>;
-type TreeNodeIndex = usize;
+pub type TreeNodeIndex = usize;
+
+/// The parameters that control how a tree grows. Each root is seeded with its own set of
+/// parameters, and every node that splits off of it carries a copy of them. This way several
+/// trees with different characters can grow side by side. The ranges are sampled for every
+/// new node.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GrowthParameters {
+    /// How much of a line's length grows every second.
+    pub grow_speed: (f64, f64),
+    pub limb_length: (f64, f64),
+    pub split_theta_range: f64,
+    pub max_tree_depth: i32,
+    pub split_count: i32,
+}
+
+/// The most branches a node can split into. The number of nodes grows exponentially with
+/// this, so it's kept small.
+pub const MAX_SPLIT_COUNT: i32 = 8;
+
+/// The deepest that a tree can grow.
+pub const MAX_TREE_DEPTH: i32 = 100;
+
+impl GrowthParameters {
+    /// Check parameters that came from JS, as some of them would never finish growing, or
+    /// would grow without bounds.
+    pub fn validate(&self) -> Result<(), String> {
+        let ranges = [
+            ("grow_speed", self.grow_speed),
+            ("limb_length", self.limb_length),
+        ];
+        for &(name, (min, max)) in &ranges {
+            if !(min.is_finite() && max.is_finite() && min > 0.0 && max > 0.0) {
+                return Err(format!(
+                    "The {} must be a range of positive numbers, but it was [{}, {}].",
+                    name, min, max
+                ));
+            }
+        }
+        if !self.split_theta_range.is_finite() {
+            return Err("The split_theta_range must be a finite number.".to_string());
+        }
+        if self.split_count < 1 || self.split_count > MAX_SPLIT_COUNT {
+            return Err(format!(
+                "The split_count must be between 1 and {}, but it was {}.",
+                MAX_SPLIT_COUNT, self.split_count
+            ));
+        }
+        if self.max_tree_depth < 0 || self.max_tree_depth > MAX_TREE_DEPTH {
+            return Err(format!(
+                "The max_tree_depth must be between 0 and {}, but it was {}.",
+                MAX_TREE_DEPTH, self.max_tree_depth
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for GrowthParameters {
+    fn default() -> GrowthParameters {
+        GrowthParameters {
+            grow_speed: (1.2, 4.8),
+            limb_length: (0.01, 0.04),
+            split_theta_range: 1.0,
+            max_tree_depth: 40,
+            split_count: 3,
+        }
+    }
+}
 
 /// The TreeNode represents a single line in a tree. It can have children. The nodes are owned
 /// by the MutableNodes vector. These are then stored in a reference counted RefCell, as there
@@ -24,6 +97,8 @@ pub struct TreeNode {
     pub start: Vector2<f64>,
     pub end: Vector2<f64>,
     pub last_drawn_end: Vector2<f64>,
+    /// How far the line had grown when it was last drawn, for partially drawing the curves.
+    pub drawn_growth_length: f64,
     pub fully_drawn: bool,
     pub growth_length: f64,
     pub depth: i32,
@@ -33,77 +108,188 @@ pub struct TreeNode {
     pub split_theta_range: f64,
     pub max_tree_depth: i32,
     pub split_count: i32,
+    pub parameters: GrowthParameters,
+    /// Which root this node grew out of.
+    pub root_index: usize,
+    /// The tick at which this node was created.
+    pub created_tick: u32,
+    /// The tick at which this node finished growing.
+    pub completed_tick: Option<u32>,
+    /// The length of all of the nodes leading back to the root.
+    pub distance_from_root: f64,
+    /// How many nodes are in the subtree starting at this node, including itself. This is
+    /// only kept up to date when it's needed for the line widths.
+    pub subtree_size: u32,
+    /// Set once this node and every node below it are settled, so that the subtree size
+    /// won't change anymore. This is kept up to date along with the subtree_size.
+    pub is_subtree_settled: bool,
+    /// The quantized line width this node was last drawn with.
+    pub drawn_line_width: u32,
+    /// Whether the whole line has been added to the PathCache.
+    pub is_cached: bool,
+    /// Set once the line is fully grown, and it's known that it won't be split.
+    pub is_terminal: bool,
+    /// Set when the line was cut short by running into another line.
+    pub is_collided: bool,
 }
 
 impl TreeNode {
-    pub fn new(start_x: f64, start_y: f64, end_x: f64, end_y: f64, depth: i32) -> TreeNode {
+    pub fn new(
+        start_x: f64,
+        start_y: f64,
+        end_x: f64,
+        end_y: f64,
+        depth: i32,
+        rng: &mut Random,
+    ) -> TreeNode {
+        TreeNode::with_parameters(
+            start_x,
+            start_y,
+            end_x,
+            end_y,
+            depth,
+            GrowthParameters::default(),
+            rng,
+        )
+    }
+
+    pub fn with_parameters(
+        start_x: f64,
+        start_y: f64,
+        end_x: f64,
+        end_y: f64,
+        depth: i32,
+        parameters: GrowthParameters,
+        rng: &mut Random,
+    ) -> TreeNode {
         TreeNode {
             start: Vector2::new(start_x, start_y),
             end: Vector2::new(end_x, end_y),
             last_drawn_end: Vector2::new(start_x, start_y),
+            drawn_growth_length: 0.0,
             growth_length: 0.0,
             fully_drawn: false,
             children: Vec::new(),
             depth: depth,
-            grow_speed: random(0.02, 0.08),
-            limb_length: random(0.01, 0.04),
-            split_theta_range: 1.0,
-            max_tree_depth: 40,
-            split_count: 3,
+            grow_speed: rng.range(parameters.grow_speed.0, parameters.grow_speed.1),
+            limb_length: rng.range(parameters.limb_length.0, parameters.limb_length.1),
+            split_theta_range: parameters.split_theta_range,
+            max_tree_depth: parameters.max_tree_depth,
+            split_count: parameters.split_count,
+            parameters,
+            root_index: 0,
+            created_tick: 0,
+            completed_tick: None,
+            distance_from_root: 0.0,
+            subtree_size: 1,
+            is_subtree_settled: false,
+            drawn_line_width: 0,
+            is_cached: false,
+            is_terminal: false,
+            is_collided: false,
         }
     }
 
-    /// Recursively descend into the data structure to create draw commands. This only performs
-    /// "ctx.move_to" and "ctx.line_to" commands, without calling "ctx.stroke".
-    pub fn draw(&mut self, nodes: &MutableNodes, page_state: &dom::PageState, force_redraw: bool) {
-        let do_redraw = force_redraw || !self.fully_drawn;
+    /// Recursively descend into the data structure to create draw commands. The lines are
+    /// added to the stroke batches based on their color and width, and are stroked later.
+    /// Lines that were already drawn are skipped. When a line gets wider, it's drawn again
+    /// over the top of itself at the new width, but if a line gets narrower this returns
+    /// true, and the whole visualization needs to be redrawn. The parent_start is the start
+    /// of the parent node, which is needed to draw smooth curves. Lines that won't change
+    /// anymore are added to the cache, and are skipped on a full redraw, as the cache is
+    /// replayed instead.
+    pub fn draw<'a>(
+        &mut self,
+        nodes: &MutableNodes,
+        batches: &mut StrokeBatches<'a>,
+        cache: &mut PathCache,
+        style: &'a ResolvedStyle,
+        view: &ViewTransform,
+        force_redraw: bool,
+        parent_start: Option<Vector2<f64>>,
+    ) -> bool {
+        let line_width = style.quantized_line_width(self);
+        let mut is_stale = false;
+        // The wider line completely covers the one that was already drawn.
+        let is_wider = line_width > self.drawn_line_width;
+        let do_redraw = (force_redraw && !self.is_cached) || !self.fully_drawn || is_wider;
+        let is_partial = !self.fully_drawn && !force_redraw && !is_wider;
         let mut end = self.end;
         let mut start = self.start;
 
         if !self.fully_drawn {
-            if !force_redraw {
+            if is_partial {
                 // Do a partial redraw only if we can.
                 start = self.last_drawn_end;
             }
-            // Grow the line out.
-            if self.growth_length == 1.0 {
+            // Grow the line out. The end of a smooth tip depends on whether it splits, so it's
+            // not fully drawn until it's settled.
+            if self.growth_length == 1.0 && (!style.smooth || self.is_settled()) {
                 // Mark as fully drawn for the next draw call.
                 self.fully_drawn = true;
             } else {
                 // Compute the beginning.
-                end = self
-                    .start
-                    .lerp(self.end, self.growth_length * cubic_out(self.growth_length))
+                end = self.end_at(self.growth_length);
             };
         }
 
         if do_redraw {
-            let dom::PageState {
-                width, height, ctx, ..
-            } = page_state;
-
-            let l = width.min(*height);
-            let w2 = width * 0.5;
-            let h2 = height * 0.5;
-
-            let theta = std::f64::consts::PI * 0.25;
-
-            let mut x0 = start.x * theta.cos() - start.y * theta.sin();
-            let mut y0 = start.x * theta.sin() + start.y * theta.cos();
-            let mut x1 = end.x * theta.cos() - end.y * theta.sin();
-            let mut y1 = end.x * theta.sin() + end.y * theta.cos();
-
-            x0 = w2 + x0 * l * 0.7;
-            y0 = h2 + y0 * l * 0.7;
-            x1 = w2 + x1 * l * 0.7;
-            y1 = h2 + y1 * l * 0.7;
-
+            let path = if style.smooth {
+                let from = if is_partial {
+                    self.drawn_growth_length
+                } else {
+                    0.0
+                };
+                // Only finish off the tip once it's drawn for the last time.
+                let is_tip = self.fully_drawn && self.is_smooth_tip();
+                self.smooth_path(parent_start, from, self.growth_length, is_tip)
+            } else {
+                vec![PathCommand::MoveTo(start), PathCommand::LineTo(end)]
+            };
             // The lines are in terms of unit interval space, convert this into canvas device pixel
-            // space, with (0, 0) centered at the top middle.
-            ctx.move_to(x0, y0);
-            ctx.line_to(x1, y1);
+            // space.
+            batches.add_path(
+                StrokeStyle {
+                    color: style.color(self),
+                    width: line_width,
+                },
+                path.into_iter()
+                    .map(|command| command.map(|point| view.unit_to_page(point))),
+            );
             // Remember the last drawn end so we can avoid re-drawing it.
             self.last_drawn_end = end;
+            self.drawn_growth_length = self.growth_length;
+            self.drawn_line_width = line_width;
+        } else if line_width != self.drawn_line_width {
+            is_stale = true;
+        }
+
+        // With the width based on the subtree size, the width keeps changing until the whole
+        // subtree is done growing. The cache is only replayed with the colors it was built
+        // with, so don't cache the lines that will still change color.
+        let is_width_settled = style.width_by != WidthBy::SubtreeSize || self.is_subtree_settled;
+        if !self.is_cached
+            && self.fully_drawn
+            && self.is_settled()
+            && is_width_settled
+            && style.is_color_settled(self)
+        {
+            let path = if style.smooth {
+                self.smooth_path(parent_start, 0.0, 1.0, self.is_smooth_tip())
+            } else {
+                vec![
+                    PathCommand::MoveTo(self.start),
+                    PathCommand::LineTo(self.end),
+                ]
+            };
+            cache.add_path(
+                StrokeStyle {
+                    color: style.color(self),
+                    width: line_width,
+                },
+                path,
+            );
+            self.is_cached = true;
         }
 
         let nodes_borrow = nodes.borrow();
@@ -114,7 +300,88 @@ impl TreeNode {
                 .borrow_mut();
 
             // Recurse into all the child nodes
-            child_node.draw(&nodes, &page_state, force_redraw)
+            if child_node.draw(
+                &nodes,
+                batches,
+                cache,
+                style,
+                view,
+                force_redraw,
+                Some(self.start),
+            ) {
+                is_stale = true;
+            }
+        }
+        is_stale
+    }
+
+    /// A line is settled once it's fully grown, and has either split or never will. After
+    /// this its path doesn't change, even when it's drawn as a smooth curve.
+    pub fn is_settled(&self) -> bool {
+        self.growth_length == 1.0 && (!self.children.is_empty() || self.is_terminal)
+    }
+
+    /// Build a smooth curve through the chain of nodes, using the midpoints of the lines as
+    /// the ends of quadratic curves, and the points where the lines meet as the control
+    /// points. Each node draws from the middle of its parent to its own middle, and the tips
+    /// of the tree finish off with a straight line to their end. The curve only depends on
+    /// the final end of the line, so it's grown out along with the line, and only the part
+    /// between the two growth lengths is built. This way the curve never has to be erased.
+    pub fn smooth_path(
+        &self,
+        parent_start: Option<Vector2<f64>>,
+        from_growth_length: f64,
+        to_growth_length: f64,
+        is_tip: bool,
+    ) -> Vec<PathCommand> {
+        let middle = self.start.lerp(self.end, 0.5);
+        let from = from_growth_length * cubic_out(from_growth_length);
+        let to = to_growth_length * cubic_out(to_growth_length);
+        let mut path = Vec::new();
+        if to > from {
+            match parent_start {
+                Some(parent_start) => {
+                    let curve = (parent_start.lerp(self.start, 0.5), self.start, middle);
+                    path.push(PathCommand::MoveTo(quadratic_blossom(curve, from, from)));
+                    path.push(PathCommand::QuadraticCurveTo(
+                        quadratic_blossom(curve, from, to),
+                        quadratic_blossom(curve, to, to),
+                    ));
+                }
+                None => {
+                    path.push(PathCommand::MoveTo(self.start.lerp(middle, from)));
+                    path.push(PathCommand::LineTo(self.start.lerp(middle, to)));
+                }
+            }
+        }
+        if is_tip {
+            // This node won't split, so finish the line.
+            path.push(PathCommand::MoveTo(middle));
+            path.push(PathCommand::LineTo(self.end));
+        }
+        path
+    }
+
+    /// The end of the line when it's drawn partially grown. The growth is eased out.
+    pub fn end_at(&self, growth_length: f64) -> Vector2<f64> {
+        self.start
+            .lerp(self.end, growth_length * cubic_out(growth_length))
+    }
+
+    fn is_smooth_tip(&self) -> bool {
+        self.growth_length == 1.0 && self.children.is_empty()
+    }
+
+    /// The growth length of the line at an earlier tick, from the recorded ticks. The line
+    /// grows the same amount for every tick until it's completed.
+    pub fn growth_length_at(&self, tick: f64) -> f64 {
+        if tick < self.created_tick as f64 {
+            return 0.0;
+        }
+        match self.completed_tick {
+            Some(completed_tick) if tick >= completed_tick as f64 => 1.0,
+            _ => ((tick - self.created_tick as f64) * self.grow_speed * STEP_DURATION)
+                .min(self.growth_length),
         }
     }
 
@@ -188,16 +455,45 @@ impl TreeNode {
         })
     }
 
+    /// Shorten this node so that it stops at the nearest intersection with any of the existing
+    /// nodes, and then add it to the R-tree. A node that runs into another one will not be
+    /// split any further. Returns true if the node was stopped by an intersection.
+    pub fn insert_and_clip(
+        &mut self,
+        node_index: TreeNodeIndex,
+        nodes: &MutableNodes,
+        r_tree: &mut RTree<TreeNodeReference>,
+    ) -> bool {
+        let intersections = self.find_intersecting_points(nodes, r_tree);
+        let nearest_intersection = self.find_nearest_intersection(intersections);
+
+        r_tree.insert(TreeNodeReference::from_node(&self, node_index));
+
+        match nearest_intersection {
+            Some(intersection) => {
+                self.end = intersection;
+                self.is_collided = true;
+                true
+            }
+            None => false,
+        }
+    }
+
     /// After this line finishes growing, the node is "split" by adding on new children nodes.
     /// These nodes are randomly rotated a little bit.
-    pub fn split(&mut self, nodes: &MutableNodes, r_tree: &mut RTree<TreeNodeReference>) {
+    pub fn split(
+        &mut self,
+        nodes: &MutableNodes,
+        r_tree: &mut RTree<TreeNodeReference>,
+        rng: &mut Random,
+        tick: u32,
+    ) {
         let new_start = &self.end;
-        let depth = self.depth + random(0.45, 1.0).round() as i32;
+        let depth = self.depth + rng.range(0.45, 1.0).round() as i32;
         let new_end = {
             // Rotate the node a bit randomly.
             let diff = self.end - self.start;
-            let drift: f64 =
-                js_sys::Math::random() * self.split_theta_range - self.split_theta_range * 0.5;
+            let drift: f64 = rng.random() * self.split_theta_range - self.split_theta_range * 0.5;
             let theta = diff.y.atan2(diff.x) + drift;
             Vector2::new(
                 new_start.x + theta.cos() * self.limb_length,
@@ -213,19 +509,20 @@ impl TreeNode {
 
         let new_node = {
             // Create the new node, and modify it if it intersects with any existing nodes.
-            let mut new_node = TreeNode::new(new_start.x, new_start.y, new_end.x, new_end.y, depth);
-            let intersections = new_node.find_intersecting_points(nodes, r_tree);
-            let nearest_intersection = new_node.find_nearest_intersection(intersections);
-
-            r_tree.insert(TreeNodeReference::from_node(&new_node, new_index));
-
-            match nearest_intersection {
-                Some(intersection) => {
-                    new_node.end = intersection;
-                    new_node.depth = new_node.max_tree_depth;
-                }
-                None => {}
-            }
+            let mut new_node = TreeNode::with_parameters(
+                new_start.x,
+                new_start.y,
+                new_end.x,
+                new_end.y,
+                depth,
+                self.parameters,
+                rng,
+            );
+            new_node.root_index = self.root_index;
+            new_node.created_tick = tick;
+            new_node.distance_from_root =
+                self.distance_from_root + (self.end - self.start).magnitude();
+            new_node.insert_and_clip(new_index, nodes, r_tree);
             new_node
         };
 
@@ -239,40 +536,46 @@ impl TreeNode {
         }
     }
 
-    /// Increase the grow length of the node, if it's not fully grown. Once the line is
-    /// fully grown, split it into two new nodes.
-    pub fn grow(&mut self, nodes: &MutableNodes, r_tree: &mut RTree<TreeNodeReference>) {
-        if self.children.len() == 0 {
-            // Grow the line.
-            self.growth_length = (self.growth_length + self.grow_speed).min(1.0);
-
-            if self.growth_length == 1.0 && self.depth < self.max_tree_depth {
-                self.growth_length = 1.0;
-                if self.start.x.abs() <= 0.5 && self.start.y.abs() <= 0.5 {
-                    for _ in 0..self.split_count {
-                        self.split(&nodes, r_tree);
-                    }
-                }
-            }
-        }
-
-        for child_node_index in &mut self.children {
-            let mut child_node = {
-                let nodes_borrow = nodes.borrow();
-                nodes_borrow
-                    .get(*child_node_index)
-                    .expect("Child node not found for the index")
-                    .clone()
-            };
+    /// Increase the grow length of the node over the elapsed time in seconds. Returns true
+    /// once the line is fully grown.
+    pub fn grow(&mut self, seconds: f64) -> bool {
+        self.growth_length = (self.growth_length + self.grow_speed * seconds).min(1.0);
+        self.growth_length == 1.0
+    }
 
-            let mut child_node_borrow = child_node
-                .try_borrow_mut()
-                .expect("Failed to get a child node during the grow method.");
+    /// A fully grown line keeps splitting until it reaches the maximum depth, runs into
+    /// another line, or leaves the growth region of the visualization.
+    pub fn can_split(&self, boundary: &Boundary) -> bool {
+        !self.is_collided && self.depth < self.max_tree_depth && boundary.contains(self.start)
+    }
 
-            child_node_borrow.grow(&nodes, r_tree);
+    /// Add on all of the children for a fully grown line.
+    pub fn split_all(
+        &mut self,
+        nodes: &MutableNodes,
+        r_tree: &mut RTree<TreeNodeReference>,
+        rng: &mut Random,
+        tick: u32,
+    ) {
+        for _ in 0..self.split_count {
+            self.split(nodes, r_tree, rng, tick);
         }
     }
 
+    /// The squared distance from a point to the closest point on this node's line.
+    pub fn distance_squared_to(&self, point: Vector2<f64>) -> f64 {
+        let line = self.end - self.start;
+        let length_squared = line.magnitude2();
+        let t = if length_squared == 0.0 {
+            0.0
+        } else {
+            ((point - self.start).dot(line) / length_squared)
+                .max(0.0)
+                .min(1.0)
+        };
+        (self.start + line * t - point).magnitude2()
+    }
+
     /// Check a true intersection between two nodes.
     pub fn intersects(&self, other: &TreeNode) -> Option<Vector2<f64>> {
         check_intersection(
@@ -329,15 +632,22 @@ fn check_intersection(
     None
 }
 
+/// The blossom of a quadratic curve. With the same value twice this is the point on the
+/// curve, and with two different values it's the control point of the part of the curve
+/// between them.
+fn quadratic_blossom(
+    (p0, p1, p2): (Vector2<f64>, Vector2<f64>, Vector2<f64>),
+    a: f64,
+    b: f64,
+) -> Vector2<f64> {
+    p0 * ((1.0 - a) * (1.0 - b)) + p1 * (a * (1.0 - b) + (1.0 - a) * b) + p2 * (a * b)
+}
+
 fn cubic_out(t: f64) -> f64 {
     let f = t - 1.0;
     return f * f * f + 1.0;
 }
 
-fn random(start: f64, end: f64) -> f64 {
-    js_sys::Math::random() * (end - start) + start
-}
-
 fn distance_squared(a: &Vector2<f64>, b: &Vector2<f64>) -> f64 {
     let x = b.x - a.x;
     let y = b.y - b.x;
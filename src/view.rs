@@ -0,0 +1,298 @@
+use cgmath::Vector2;
+use dom::PageState;
+
+/// The camera controls which part of the unit interval space is being looked at. It's changed
+/// by panning and zooming the canvas, and can be configured from JS.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Camera {
+    /// The point in the unit interval space that is shown in the center of the canvas.
+    pub center: (f64, f64),
+    /// The amount of magnification, where 1.0 fits the whole tree on the canvas.
+    pub zoom: f64,
+    /// The rotation in radians.
+    pub rotation: f64,
+}
+
+/// The zoom is kept within this range, as the view can't be mapped back into the unit
+/// interval space once the scale reaches zero.
+pub const MIN_ZOOM: f64 = 0.05;
+pub const MAX_ZOOM: f64 = 200.0;
+
+impl Default for Camera {
+    fn default() -> Camera {
+        Camera {
+            center: (0.0, 0.0),
+            zoom: 1.0,
+            rotation: std::f64::consts::PI * 0.25,
+        }
+    }
+}
+
+/// How the unit interval space is framed within the canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FramingMode {
+    /// Fit the whole growth region within the smallest dimension of the canvas. This leaves
+    /// empty space on the sides of a wide canvas.
+    Fit,
+    /// Fill the largest dimension of the canvas, cropping the growth region.
+    Fill,
+    /// Stretch the growth region into a rectangle matching the aspect ratio of the canvas.
+    Stretch,
+}
+
+/// What happens to the growth region when the canvas is resized.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeBehavior {
+    /// Keep the growth region from when the framing was set.
+    Keep,
+    /// Adapt the growth region to the new aspect ratio. This only affects the lines that
+    /// split after the resize.
+    Adapt,
+}
+
+/// The framing can be configured from JS.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Framing {
+    pub mode: FramingMode,
+    pub on_resize: ResizeBehavior,
+}
+
+impl Default for Framing {
+    fn default() -> Framing {
+        Framing {
+            mode: FramingMode::Fit,
+            on_resize: ResizeBehavior::Keep,
+        }
+    }
+}
+
+/// The region of the unit interval space that the trees can split within. It's a rectangle
+/// centered on (0, 0), which is rotated along with the view, so that it can line up with
+/// the canvas.
+#[derive(Clone, Copy, Debug)]
+pub struct Boundary {
+    pub half_size: Vector2<f64>,
+    pub rotation: f64,
+}
+
+impl Default for Boundary {
+    /// The unit square.
+    fn default() -> Boundary {
+        Boundary {
+            half_size: Vector2::new(0.5, 0.5),
+            rotation: 0.0,
+        }
+    }
+}
+
+impl Boundary {
+    /// Create the growth region for the current aspect ratio of the canvas.
+    pub fn new(page: &PageState, camera: &Camera, framing: &Framing) -> Boundary {
+        let mut boundary = match framing.mode {
+            FramingMode::Fit => Boundary::default(),
+            FramingMode::Fill => {
+                // Only the part of the unit square that's on the canvas is visible.
+                let max = page.width.max(page.height).max(1.0);
+                Boundary {
+                    half_size: Vector2::new(0.5 * page.width / max, 0.5 * page.height / max),
+                    rotation: 0.0,
+                }
+            }
+            FramingMode::Stretch => {
+                let min = page.width.min(page.height).max(1.0);
+                Boundary {
+                    half_size: Vector2::new(0.5 * page.width / min, 0.5 * page.height / min)
+                        .map(|size| size.max(0.5)),
+                    rotation: 0.0,
+                }
+            }
+        };
+        boundary.follow_camera(camera, framing);
+        boundary
+    }
+
+    /// The rectangle of the fill and stretch modes lines up with the canvas, so it turns
+    /// along with the camera. The size stays the same.
+    pub fn follow_camera(&mut self, camera: &Camera, framing: &Framing) {
+        if framing.mode != FramingMode::Fit {
+            self.rotation = camera.rotation;
+        }
+    }
+
+    pub fn contains(&self, point: Vector2<f64>) -> bool {
+        // Rotate the point the same way that the view does.
+        let (sin, cos) = self.rotation.sin_cos();
+        let x = point.x * cos - point.y * sin;
+        let y = point.x * sin + point.y * cos;
+        x.abs() <= self.half_size.x && y.abs() <= self.half_size.y
+    }
+}
+
+impl Camera {
+    /// Check a camera that came from JS, and clamp the zoom into its range.
+    pub fn validate(mut self) -> Result<Camera, String> {
+        if !self.center.0.is_finite() || !self.center.1.is_finite() || !self.rotation.is_finite() {
+            return Err("The camera center and rotation must be finite numbers.".to_string());
+        }
+        if !(self.zoom.is_finite() && self.zoom > 0.0) {
+            return Err(format!(
+                "The camera zoom must be a positive number, but it was {}.",
+                self.zoom
+            ));
+        }
+        self.zoom = self.zoom.max(MIN_ZOOM).min(MAX_ZOOM);
+        Ok(self)
+    }
+
+    /// Move the camera so that the point under "from" ends up under "to", where both points
+    /// are in canvas device pixel space.
+    pub fn pan(
+        &mut self,
+        page: &PageState,
+        framing: &Framing,
+        from: Vector2<f64>,
+        to: Vector2<f64>,
+    ) {
+        let view = ViewTransform::new(page, self, framing);
+        let offset = view.page_to_unit(from) - view.page_to_unit(to);
+        self.center.0 += offset.x;
+        self.center.1 += offset.y;
+    }
+
+    /// Zoom in or out by a factor, while keeping the point under the pointer in place.
+    pub fn zoom_at(
+        &mut self,
+        page: &PageState,
+        framing: &Framing,
+        point: Vector2<f64>,
+        factor: f64,
+    ) {
+        let before = ViewTransform::new(page, self, framing).page_to_unit(point);
+        self.zoom = (self.zoom * factor).max(MIN_ZOOM).min(MAX_ZOOM);
+        let after = ViewTransform::new(page, self, framing).page_to_unit(point);
+        self.center.0 += before.x - after.x;
+        self.center.1 += before.y - after.y;
+    }
+}
+
+/// The nodes live in a unit interval space, centered around (0, 0). The ViewTransform maps
+/// this space into canvas device pixel space, and back again. By default the tree is rotated
+/// by 45 degrees, scaled to fit within the smallest dimension of the canvas (or the largest,
+/// depending on the framing), and then centered. The camera then pans, zooms, and rotates this view.
+#[derive(Clone, Copy, Debug)]
+pub struct ViewTransform {
+    /// The center of the canvas in device pixels.
+    pub center: Vector2<f64>,
+    /// The point in unit interval space that is drawn at the center of the canvas.
+    pub target: Vector2<f64>,
+    /// How many device pixels a unit in the node space takes up.
+    pub scale: f64,
+    /// The rotation in radians.
+    pub rotation: f64,
+}
+
+impl ViewTransform {
+    pub fn new(page: &PageState, camera: &Camera, framing: &Framing) -> ViewTransform {
+        ViewTransform::with_size(page.width, page.height, camera, framing)
+    }
+
+    /// Frame the view within a rectangle of any size, such as a printed page, in the same way
+    /// that it's framed within the canvas.
+    pub fn with_size(width: f64, height: f64, camera: &Camera, framing: &Framing) -> ViewTransform {
+        let size = match framing.mode {
+            FramingMode::Fit | FramingMode::Stretch => width.min(height),
+            FramingMode::Fill => width.max(height),
+        };
+        ViewTransform {
+            center: Vector2::new(width * 0.5, height * 0.5),
+            target: Vector2::new(camera.center.0, camera.center.1),
+            scale: size * 0.7 * camera.zoom,
+            rotation: camera.rotation,
+        }
+    }
+
+    /// Map a point from the unit interval space into canvas device pixel space.
+    pub fn unit_to_page(&self, point: Vector2<f64>) -> Vector2<f64> {
+        let (sin, cos) = self.rotation.sin_cos();
+        let point = point - self.target;
+        Vector2::new(
+            self.center.x + (point.x * cos - point.y * sin) * self.scale,
+            self.center.y + (point.x * sin + point.y * cos) * self.scale,
+        )
+    }
+
+    /// The same mapping as unit_to_page, as the [a, b, c, d, e, f] values for the canvas
+    /// setTransform.
+    pub fn matrix(&self) -> [f64; 6] {
+        let (sin, cos) = self.rotation.sin_cos();
+        let (a, b) = (cos * self.scale, sin * self.scale);
+        [
+            a,
+            b,
+            -b,
+            a,
+            self.center.x - (self.target.x * a - self.target.y * b),
+            self.center.y - (self.target.x * b + self.target.y * a),
+        ]
+    }
+
+    /// Map a point from canvas device pixel space back into the unit interval space. This is
+    /// the inverse of unit_to_page.
+    pub fn page_to_unit(&self, point: Vector2<f64>) -> Vector2<f64> {
+        let (sin, cos) = self.rotation.sin_cos();
+        let x = (point.x - self.center.x) / self.scale;
+        let y = (point.y - self.center.y) / self.scale;
+        self.target + Vector2::new(x * cos + y * sin, -x * sin + y * cos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_to_unit_inverts_unit_to_page() {
+        let camera = Camera {
+            center: (0.1, -0.2),
+            zoom: 2.5,
+            rotation: 0.7,
+        };
+        for &mode in &[FramingMode::Fit, FramingMode::Fill, FramingMode::Stretch] {
+            let framing = Framing {
+                mode,
+                ..Framing::default()
+            };
+            let view = ViewTransform::with_size(800.0, 450.0, &camera, &framing);
+            for &(x, y) in &[(0.0, 0.0), (0.5, -0.5), (-0.3, 0.25), (1.5, 2.0)] {
+                let point = Vector2::new(x, y);
+                let round_trip = view.page_to_unit(view.unit_to_page(point));
+                assert!(
+                    (round_trip.x - x).abs() < 1e-9,
+                    "{:?} {:?}",
+                    point,
+                    round_trip
+                );
+                assert!(
+                    (round_trip.y - y).abs() < 1e-9,
+                    "{:?} {:?}",
+                    point,
+                    round_trip
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matrix_matches_unit_to_page() {
+        let view = ViewTransform::with_size(300.0, 600.0, &Camera::default(), &Framing::default());
+        let [a, b, c, d, e, f] = view.matrix();
+        let point = Vector2::new(0.3, -0.4);
+        let page = view.unit_to_page(point);
+        assert!((a * point.x + c * point.y + e - page.x).abs() < 1e-9);
+        assert!((b * point.x + d * point.y + f - page.y).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,467 @@
+use cgmath::Vector2;
+use dom;
+use draw;
+use gallery;
+use input;
+use input::PointerInput;
+use preset;
+use scheduler;
+use stats;
+use std::cell::RefCell;
+use std::rc::Rc;
+use style;
+use timeline;
+use tree_canvas::{from_js, from_js_or_default, MountOptions, COMPLETE_EVENT};
+use view;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+/// This module runs the visualization inside of a Web Worker, so that growing and drawing the
+/// trees doesn't block the main thread. The main thread transfers control of the canvas to the
+/// worker as an OffscreenCanvas, and then forwards along the resize and pointer events as
+/// messages. The worker runs the same draw::tick as a TreeCanvas on the main thread.
+
+/// The messages that are posted from the main thread to the worker. These are plain JS
+/// objects with a "type" property, e.g. { type: "set_style", style: { ... } }, so JS can post
+/// them to the worker as well. All positions and sizes are in device pixels.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorkerMessage {
+    Resize {
+        width: f64,
+        height: f64,
+        device_pixel_ratio: f64,
+    },
+    PointerDown {
+        x: f64,
+        y: f64,
+        shift_key: bool,
+    },
+    PointerMove {
+        x: f64,
+        y: f64,
+        buttons: u16,
+    },
+    PointerUp {
+        x: f64,
+        y: f64,
+    },
+    PointerCancel,
+    Wheel {
+        x: f64,
+        y: f64,
+        delta: f64,
+    },
+    AddRoot {
+        config: draw::RootConfig,
+    },
+    SetRoots {
+        configs: Vec<draw::RootConfig>,
+    },
+    SetCamera {
+        camera: view::Camera,
+    },
+    SetStyle {
+        style: style::Style,
+    },
+    SetBudget {
+        budget: scheduler::Budget,
+    },
+    SetSpeed {
+        speed: f64,
+    },
+    SetFraming {
+        framing: view::Framing,
+    },
+    Scrub {
+        tick: f64,
+    },
+    PlayTimeline {
+        rate: f64,
+    },
+    JumpToEnd,
+    ResumeGrowth,
+    StartGallery {
+        #[serde(default)]
+        options: gallery::GalleryOptions,
+    },
+    StopGallery,
+    LoadPreset {
+        name: String,
+    },
+    RegisterPreset {
+        name: String,
+        preset: preset::Preset,
+    },
+}
+
+/// The messages that are posted from the worker back to the main thread.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorkerEvent {
+    /// The trees are done growing, with the final statistics.
+    Complete { stats: stats::Summary },
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// Post a message from inside of the worker back to the main thread.
+    #[wasm_bindgen(js_name = postMessage)]
+    fn post_to_main_thread(message: &JsValue);
+}
+
+/// The initial size of the canvas, which is sent along with the OffscreenCanvas in the "init"
+/// message.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WorkerOptions {
+    pub width: f64,
+    pub height: f64,
+    pub device_pixel_ratio: f64,
+    pub transparent: bool,
+    #[serde(default)]
+    pub framing: view::Framing,
+}
+
+/// The WorkerCanvas is constructed inside of the worker once it receives the "init" message,
+/// and then handles every message after that.
+#[wasm_bindgen]
+pub struct WorkerCanvas {
+    canvas: web_sys::OffscreenCanvas,
+    state: Rc<RefCell<draw::State>>,
+    input: PointerInput,
+}
+
+#[wasm_bindgen]
+impl WorkerCanvas {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        canvas: web_sys::OffscreenCanvas,
+        options: JsValue,
+    ) -> Result<WorkerCanvas, JsValue> {
+        let options: WorkerOptions = from_js(options)?;
+        canvas.set_width(options.width as u32);
+        canvas.set_height(options.height as u32);
+
+        let state = Rc::new(RefCell::new(draw::init(dom::PageState {
+            width: options.width,
+            height: options.height,
+            device_pixel_ratio: options.device_pixel_ratio,
+            is_resized: true,
+            alpha: options.transparent,
+            ctx: dom::get_offscreen_context(&canvas, options.transparent)?,
+        })));
+        state.borrow_mut().style.transparent = Some(options.transparent);
+        draw::set_framing(&mut state.borrow_mut(), options.framing);
+
+        // The worker runs for as long as the page, so the loop is never stopped.
+        std::mem::forget(dom::start_raf({
+            let state = state.clone();
+            move |timestamp| {
+                let mut state = state.borrow_mut();
+                draw::tick(&mut state, timestamp);
+                state.page.is_resized = false;
+                if let Some(stats) = draw::take_completion(&mut state) {
+                    post_to_main_thread(
+                        &JsValue::from_serde(&WorkerEvent::Complete { stats }).unwrap(),
+                    );
+                }
+            }
+        }));
+
+        Ok(WorkerCanvas {
+            canvas,
+            state,
+            input: PointerInput::default(),
+        })
+    }
+
+    /// Handle a message that was posted from the main thread.
+    pub fn handle_message(&mut self, message: JsValue) -> Result<(), JsValue> {
+        let message: WorkerMessage = from_js(message)?;
+        let mut state = self.state.borrow_mut();
+        match message {
+            WorkerMessage::Resize {
+                width,
+                height,
+                device_pixel_ratio,
+            } => {
+                // Changing the size of the OffscreenCanvas clears it, and the next tick will
+                // redraw everything.
+                self.canvas.set_width(width as u32);
+                self.canvas.set_height(height as u32);
+                state.page.width = width;
+                state.page.height = height;
+                state.page.device_pixel_ratio = device_pixel_ratio;
+                state.page.is_resized = true;
+            }
+            WorkerMessage::PointerDown { x, y, shift_key } => {
+                self.input.pointer_down(Vector2::new(x, y), shift_key);
+            }
+            WorkerMessage::PointerMove { x, y, buttons } => {
+                self.input
+                    .pointer_move(&mut state, Vector2::new(x, y), buttons);
+            }
+            WorkerMessage::PointerUp { x, y } => {
+                self.input.pointer_up(&mut state, Vector2::new(x, y));
+            }
+            WorkerMessage::PointerCancel => {
+                self.input.pointer_cancel();
+            }
+            WorkerMessage::Wheel { x, y, delta } => {
+                self.input.wheel(&mut state, Vector2::new(x, y), delta);
+            }
+            WorkerMessage::AddRoot { config } => {
+                draw::add_root(&mut state, &config);
+            }
+            WorkerMessage::SetRoots { configs } => {
+                draw::set_roots(&mut state, &configs);
+            }
+            WorkerMessage::SetCamera { camera } => {
+                draw::set_camera(&mut state, camera).map_err(|error| JsValue::from_str(&error))?;
+            }
+            WorkerMessage::SetStyle { style } => {
+                draw::set_style(&mut state, style).map_err(|error| JsValue::from_str(&error))?;
+            }
+            WorkerMessage::SetBudget { budget } => {
+                state.scheduler.budget = budget;
+            }
+            WorkerMessage::SetSpeed { speed } => {
+                state.scheduler.speed = speed.max(0.0);
+            }
+            WorkerMessage::SetFraming { framing } => {
+                draw::set_framing(&mut state, framing);
+            }
+            WorkerMessage::Scrub { tick } => {
+                timeline::scrub(&mut state, tick);
+            }
+            WorkerMessage::PlayTimeline { rate } => {
+                timeline::play(&mut state, rate);
+            }
+            WorkerMessage::JumpToEnd => {
+                let end = state.tick_count as f64;
+                timeline::scrub(&mut state, end);
+            }
+            WorkerMessage::ResumeGrowth => {
+                timeline::resume_growth(&mut state);
+            }
+            WorkerMessage::StartGallery { options } => {
+                gallery::start(&mut state, options);
+            }
+            WorkerMessage::StopGallery => {
+                gallery::stop(&mut state);
+            }
+            WorkerMessage::LoadPreset { name } => {
+                preset::load(&mut state, &name).map_err(|error| JsValue::from_str(&error))?;
+            }
+            WorkerMessage::RegisterPreset { name, preset } => {
+                // The worker has its own set of presets, separate from the main thread.
+                preset::register(&name, preset).map_err(|error| JsValue::from_str(&error))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transfer the canvas to a new worker, and forward the resize and pointer events to it. The
+/// worker script is expected to construct a WorkerCanvas from the "init" message, and pass
+/// every other message to WorkerCanvas.handle_message.
+pub fn mount(
+    canvas: web_sys::HtmlCanvasElement,
+    worker_url: &str,
+    options: &MountOptions,
+) -> Result<web_sys::Worker, JsValue> {
+    let worker = web_sys::Worker::new(worker_url)?;
+    let device_pixel_ratio = dom::window().device_pixel_ratio();
+    let (width, height) = match options.size {
+        Some((width, height)) => {
+            dom::set_canvas_css_size(&canvas, width, height);
+            // The worker can't be unmounted, so the watcher lives as long as the page.
+            std::mem::forget(dom::on_device_pixel_ratio_change({
+                let worker = worker.clone();
+                move || {
+                    let device_pixel_ratio = dom::window().device_pixel_ratio();
+                    post(
+                        &worker,
+                        &WorkerMessage::Resize {
+                            width: (width * device_pixel_ratio).round(),
+                            height: (height * device_pixel_ratio).round(),
+                            device_pixel_ratio,
+                        },
+                    );
+                }
+            }));
+            (
+                (width * device_pixel_ratio).round(),
+                (height * device_pixel_ratio).round(),
+            )
+        }
+        None => {
+            // The worker resizes the backing store, which would grow an unsized canvas.
+            dom::pin_intrinsic_css_size(&canvas);
+            let post_size = {
+                let canvas = canvas.clone();
+                let worker = worker.clone();
+                move || {
+                    let (width, height) = dom::element_device_pixel_size(canvas.as_ref());
+                    post(
+                        &worker,
+                        &WorkerMessage::Resize {
+                            width,
+                            height,
+                            device_pixel_ratio: dom::window().device_pixel_ratio(),
+                        },
+                    );
+                }
+            };
+            // The worker can't be unmounted, so these live as long as the page.
+            std::mem::forget(dom::on_element_resize(canvas.as_ref(), post_size.clone()));
+            std::mem::forget(dom::on_device_pixel_ratio_change(post_size));
+            dom::element_device_pixel_size(canvas.as_ref())
+        }
+    };
+
+    // After this the canvas element can no longer be resized or drawn to from the main thread.
+    let offscreen = canvas.transfer_control_to_offscreen()?;
+    let options = JsValue::from_serde(&WorkerOptions {
+        width,
+        height,
+        device_pixel_ratio,
+        transparent: options.transparent,
+        framing: options.framing,
+    })
+    .unwrap();
+    let message = js_sys::Object::new();
+    js_sys::Reflect::set(&message, &"type".into(), &"init".into())?;
+    js_sys::Reflect::set(&message, &"canvas".into(), &offscreen)?;
+    js_sys::Reflect::set(&message, &"options".into(), &options)?;
+    worker.post_message_with_transfer(&message, &js_sys::Array::of1(&offscreen))?;
+
+    forward_input(&canvas, &worker);
+    listen_to_worker(&canvas, &worker);
+    Ok(worker)
+}
+
+fn post(worker: &web_sys::Worker, message: &WorkerMessage) {
+    worker
+        .post_message(&JsValue::from_serde(message).unwrap())
+        .expect("Unable to post a message to the worker.");
+}
+
+/// The pointer events can't be listened to from inside of the worker, so listen to them on
+/// the main thread, and post them over in device pixel space. The worker can't be unmounted, so
+/// the listeners live as long as the page.
+fn forward_input(canvas: &web_sys::HtmlCanvasElement, worker: &web_sys::Worker) {
+    let pointer_down = dom::on_canvas_pointer_event(canvas, "pointerdown", {
+        let canvas = canvas.clone();
+        let worker = worker.clone();
+        move |event| {
+            dom::capture_pointer(&canvas, &event);
+            let position = input::event_position(&canvas, &event);
+            post(
+                &worker,
+                &WorkerMessage::PointerDown {
+                    x: position.x,
+                    y: position.y,
+                    shift_key: event.shift_key(),
+                },
+            );
+        }
+    });
+
+    let pointer_move = dom::on_canvas_pointer_event(canvas, "pointermove", {
+        let canvas = canvas.clone();
+        let worker = worker.clone();
+        move |event| {
+            let position = input::event_position(&canvas, &event);
+            post(
+                &worker,
+                &WorkerMessage::PointerMove {
+                    x: position.x,
+                    y: position.y,
+                    buttons: event.buttons(),
+                },
+            );
+        }
+    });
+
+    let pointer_cancel = dom::on_canvas_pointer_event(canvas, "pointercancel", {
+        let worker = worker.clone();
+        move |_| {
+            post(&worker, &WorkerMessage::PointerCancel);
+        }
+    });
+
+    let pointer_up = dom::on_canvas_pointer_event(canvas, "pointerup", {
+        let canvas = canvas.clone();
+        let worker = worker.clone();
+        move |event| {
+            let position = input::event_position(&canvas, &event);
+            post(
+                &worker,
+                &WorkerMessage::PointerUp {
+                    x: position.x,
+                    y: position.y,
+                },
+            );
+        }
+    });
+
+    let wheel = dom::on_canvas_wheel_event(canvas, {
+        let canvas = canvas.clone();
+        let worker = worker.clone();
+        move |event| {
+            event.prevent_default();
+            let position = input::event_position(&canvas, &event);
+            post(
+                &worker,
+                &WorkerMessage::Wheel {
+                    x: position.x,
+                    y: position.y,
+                    delta: input::wheel_delta(&event),
+                },
+            );
+        }
+    });
+
+    std::mem::forget(vec![
+        pointer_down,
+        pointer_move,
+        pointer_cancel,
+        pointer_up,
+        wheel,
+    ]);
+}
+
+/// Dispatch the events from the worker on the canvas, in the same way as a TreeCanvas.
+fn listen_to_worker(canvas: &web_sys::HtmlCanvasElement, worker: &web_sys::Worker) {
+    let canvas = canvas.clone();
+    let closure = Closure::wrap(Box::new(move |event: web_sys::MessageEvent| {
+        match event.data().into_serde() {
+            Ok(WorkerEvent::Complete { stats }) => {
+                let detail = JsValue::from_serde(&stats).unwrap();
+                dom::dispatch_custom_event(canvas.as_ref(), COMPLETE_EVENT, &detail);
+            }
+            // Leave any other messages to the page.
+            Err(_) => {}
+        }
+    }) as Box<FnMut(web_sys::MessageEvent)>);
+    (worker.as_ref() as &web_sys::EventTarget)
+        .add_event_listener_with_callback("message", closure.as_ref().unchecked_ref())
+        .unwrap();
+    closure.forget();
+}
+
+/// Mount a visualization that runs in a worker from JS. The canvas is either a canvas element,
+/// or a CSS selector for one. The options are an object matching MountOptions, or undefined.
+/// Returns the Worker, so that JS can post messages to it, e.g.
+/// worker.postMessage({ type: "set_style", style: { palette: "fire" } }).
+#[wasm_bindgen]
+pub fn mount_worker(
+    canvas: JsValue,
+    worker_url: &str,
+    options: JsValue,
+) -> Result<web_sys::Worker, JsValue> {
+    let canvas = dom::canvas_from_js(canvas)?;
+    let options: MountOptions = from_js_or_default(options)?;
+    mount(canvas, worker_url, &options)
+}
@@ -3,17 +3,30 @@ const HtmlWebpackPlugin = require('html-webpack-plugin');
 const webpack = require('webpack');
 const CopyWebpackPlugin = require('copy-webpack-plugin')
 
-module.exports = {
-  entry: './index.js',
-  output: {
-    path: path.resolve(__dirname, 'dist'),
-    filename: 'index.js',
+module.exports = [
+  {
+    entry: './index.js',
+    output: {
+      path: path.resolve(__dirname, 'dist'),
+      filename: 'index.js',
+    },
+    plugins: [
+      new HtmlWebpackPlugin({
+          template: "index.html",
+      }),
+      new CopyWebpackPlugin([{ from: 'assets' }])
+    ],
+    mode: 'development'
   },
-  plugins: [
-    new HtmlWebpackPlugin({
-        template: "index.html",
-    }),
-    new CopyWebpackPlugin([{ from: 'assets' }])
-  ],
-  mode: 'development'
-};
+  {
+    // The worker is built separately, as it runs in a different global scope.
+    entry: './worker.js',
+    target: 'webworker',
+    output: {
+      path: path.resolve(__dirname, 'dist'),
+      filename: 'worker.js',
+      chunkFilename: 'worker.[id].js',
+    },
+    mode: 'development'
+  }
+];
@@ -0,0 +1,26 @@
+// This worker draws the visualization onto an OffscreenCanvas. The main thread sets it up
+// with mount_worker, or with a data-worker="worker.js" attribute on the #canvas element.
+let workerCanvas = null;
+const pending = [];
+
+function handleMessage(canvasExample, data) {
+  if (data.type === 'init') {
+    workerCanvas = new canvasExample.WorkerCanvas(data.canvas, data.options);
+  } else {
+    workerCanvas.handle_message(data);
+  }
+}
+
+// Messages can arrive before the wasm has finished loading, so queue them up.
+self.onmessage = ({ data }) => pending.push(data);
+
+import('./target/js/canvas')
+  .then(canvasExample => {
+    self.onmessage = ({ data }) => handleMessage(canvasExample, data);
+    for (const data of pending.splice(0)) {
+      handleMessage(canvasExample, data);
+    }
+  })
+  .catch((error) => {
+    console.error('There was an error when importing the file in the worker', error)
+  });